@@ -0,0 +1,274 @@
+/// Admin API for the daemon operator, under /_pokem/admin/
+use crate::config::*;
+use crate::daemon::get_query_params;
+use crate::token::{load_tokens, save_tokens};
+use crate::utils::*;
+
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::AUTHORIZATION;
+use hyper::{HeaderMap, Method, Response, StatusCode, Uri};
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::ruma::api::client::filter::FilterDefinition;
+use matrix_sdk::ruma::events::tag::TagInfo;
+use matrix_sdk::ruma::UInt;
+use matrix_sdk::Room;
+use matrix_sdk_base::store::StateStoreDataKey;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{error, info};
+
+use std::convert::Infallible;
+
+/// The path prefix of the admin API
+pub const ADMIN_PREFIX: &str = "_pokem/admin/";
+
+/// A joined room, as listed by the admin API
+#[derive(Debug, Serialize)]
+struct RoomStatus {
+    room_id: String,
+    alias: Option<String>,
+    name: Option<String>,
+    members: u64,
+    blocked: bool,
+    /// Whether pokes need a token or signature
+    auth: bool,
+    /// Names of the room's tokens
+    tokens: Vec<String>,
+    signing: bool,
+}
+
+impl RoomStatus {
+    /// Get the status from the synced state, without asking the homeserver
+    async fn from_room(room: &Room) -> Self {
+        let tags = room.tags().await.ok().flatten().unwrap_or_default();
+        let has_tag = |tag: &str| tags.keys().any(|t| t.to_string() == tag);
+        let tokens: Vec<String> = load_tokens(room)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|token| token.name)
+            .collect();
+        let legacy_token = tags.keys().any(|t| {
+            t.to_string().starts_with("dev.pokem.auth.")
+                || t.to_string().starts_with("dev.pokem.pass.")
+        });
+        let signing = has_tag("dev.pokem.signing");
+        RoomStatus {
+            room_id: room.room_id().to_string(),
+            alias: room.canonical_alias().map(|alias| alias.to_string()),
+            name: room.name(),
+            members: room.joined_members_count(),
+            blocked: has_tag("dev.pokem.block"),
+            auth: !tokens.is_empty() || legacy_token || signing,
+            tokens,
+            signing,
+        }
+    }
+}
+
+fn respond(status: StatusCode, body: impl Into<Bytes>) -> Response<BoxBody<Bytes, Infallible>> {
+    Response::builder()
+        .status(status)
+        .body(Full::new(body.into()).boxed())
+        .unwrap()
+}
+
+/// Check the `Authorization: Bearer <token>` header against the admin token.
+///
+/// Requests with an `auth` query parameter are refused, the token would end up in logs.
+/// The hashes are compared in constant time, so the time taken doesn't reveal the token.
+fn is_admin(headers: &HeaderMap, uri: &Uri, admin_token: &str) -> bool {
+    if get_query_params(uri).contains_key("auth") {
+        return false;
+    }
+    let Some(token) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+    else {
+        return false;
+    };
+    let (token, admin_token) = (Sha256::digest(token), Sha256::digest(admin_token));
+    token
+        .iter()
+        .zip(admin_token.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Handle a request to the admin API.
+///
+/// `path` is the path after the admin prefix, e.g. "rooms" or "rooms/<room>/block".
+pub async fn admin_request(
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    path: &str,
+) -> anyhow::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let admin_token = GLOBAL_CONFIG
+        .lock()
+        .unwrap()
+        .as_ref()
+        .unwrap()
+        .daemon
+        .as_ref()
+        .and_then(|d| d.admin_token.clone());
+    // The admin API is disabled without a token
+    let Some(admin_token) = admin_token.filter(|token| !token.is_empty()) else {
+        return Ok(respond(StatusCode::NOT_FOUND, "Not found"));
+    };
+    if !is_admin(headers, uri, &admin_token) {
+        return Ok(respond(
+            StatusCode::UNAUTHORIZED,
+            "Incorrect Authentication Token",
+        ));
+    }
+
+    let bot = GLOBAL_BOT.lock().unwrap().as_ref().unwrap().clone();
+    let parts: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    match (method, parts.as_slice()) {
+        (&Method::GET, ["rooms"]) => {
+            let mut rooms = Vec::new();
+            for room in bot.client().joined_rooms() {
+                rooms.push(RoomStatus::from_room(&room).await);
+            }
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(Full::new(Bytes::from(serde_json::to_string(&rooms)?)).boxed())
+                .unwrap())
+        }
+        (&Method::POST, ["rooms", room_id, action]) => {
+            let room_id = urlencoding::decode(room_id).map_or(room_id.to_string(), |r| r.into());
+            let Some(room) = get_room_from_name(&bot, &room_id).await else {
+                return Ok(respond(StatusCode::NOT_FOUND, "Room not found"));
+            };
+            info!("Admin: {} {}", action, room.room_id());
+            match *action {
+                "leave" => room.leave().await?,
+                "block" => {
+                    room.set_tag("dev.pokem.block".into(), TagInfo::default())
+                        .await?;
+                }
+                "unblock" => {
+                    room.remove_tag("dev.pokem.block".into()).await?;
+                }
+                "reset-tokens" => {
+                    // Reading the config moves any plaintext token into the tokens,
+                    // and writing it removes the plaintext tags
+                    let mut config = get_room_config(&room).await;
+                    if config.unavailable {
+                        return Ok(respond(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Failed to read the room's config",
+                        ));
+                    }
+                    save_tokens(&room, Vec::new()).await?;
+                    // Clear the secret too, so the room is left open rather than half locked
+                    config.secret = None;
+                    config.signing = false;
+                    set_room_config(&room, config).await;
+                }
+                _ => return Ok(respond(StatusCode::NOT_FOUND, "Unknown action")),
+            }
+            Ok(respond(StatusCode::OK, "OK"))
+        }
+        (&Method::POST, ["sync"]) => {
+            // Refresh the full state of every room from the current sync token.
+            // The timeline is left out, the bot's own sync loop handles new messages and
+            // the handlers would otherwise run again for them
+            let token = bot
+                .client()
+                .store()
+                .get_kv_data(StateStoreDataKey::SyncToken)
+                .await?
+                .and_then(|value| value.into_sync_token());
+            tokio::task::spawn(async move {
+                let mut filter = FilterDefinition::with_lazy_loading();
+                filter.room.timeline.limit = Some(UInt::MIN);
+                let mut settings = SyncSettings::default()
+                    .filter(filter.into())
+                    .full_state(true);
+                if let Some(token) = token {
+                    settings = settings.token(token);
+                }
+                match bot.client().sync_once(settings).await {
+                    Ok(_) => info!("Admin: resync finished"),
+                    Err(e) => error!("Admin: resync failed: {:?}", e),
+                }
+            });
+            Ok(respond(StatusCode::ACCEPTED, "Resync started"))
+        }
+        _ => Ok(respond(StatusCode::NOT_FOUND, "Not found")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADMIN_TOKEN: &str = "admin-token";
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    fn admin(headers: &HeaderMap, uri: &str) -> bool {
+        is_admin(headers, &uri.parse().unwrap(), ADMIN_TOKEN)
+    }
+
+    #[test]
+    fn bearer_token() {
+        let uri = "/_pokem/admin/rooms";
+        assert!(admin(&headers("authorization", "Bearer admin-token"), uri));
+        assert!(admin(
+            &headers("authorization", "bearer  admin-token "),
+            uri
+        ));
+    }
+
+    #[test]
+    fn wrong_token() {
+        let uri = "/_pokem/admin/rooms";
+        assert!(!admin(&HeaderMap::new(), uri));
+        assert!(!admin(&headers("authorization", "Bearer wrong"), uri));
+        assert!(!admin(
+            &headers("authorization", "Bearer admin-token2"),
+            uri
+        ));
+        assert!(!admin(&headers("authorization", "Bearer admin"), uri));
+        assert!(!admin(&headers("authorization", "Bearer "), uri));
+        assert!(!admin(&headers("authorization", "admin-token"), uri));
+        // Basic auth and the room token headers aren't accepted
+        assert!(!admin(
+            &headers("authorization", "Basic OmFkbWluLXRva2Vu"),
+            uri
+        ));
+        assert!(!admin(&headers("authentication", ADMIN_TOKEN), uri));
+        assert!(!admin(&headers("auth", ADMIN_TOKEN), uri));
+    }
+
+    #[test]
+    fn query_token() {
+        assert!(!admin(
+            &HeaderMap::new(),
+            "/_pokem/admin/rooms?auth=admin-token"
+        ));
+        // "Bearer admin-token", base64 encoded like ntfy
+        assert!(!admin(
+            &HeaderMap::new(),
+            "/_pokem/admin/rooms?auth=QmVhcmVyIGFkbWluLXRva2Vu"
+        ));
+        // Even with the right header, so the token isn't sent in the query
+        assert!(!admin(
+            &headers("authorization", "Bearer admin-token"),
+            "/_pokem/admin/rooms?auth=admin-token"
+        ));
+    }
+}
@@ -0,0 +1,353 @@
+/// File attachments for pokes
+use matrix_sdk::ruma::events::room::message::{
+    AudioInfo, AudioMessageEventContent, FileInfo, FileMessageEventContent,
+    ImageMessageEventContent, MessageType, RoomMessageEventContent, VideoInfo,
+    VideoMessageEventContent,
+};
+use matrix_sdk::ruma::events::room::{ImageInfo, MediaSource};
+use matrix_sdk::ruma::UInt;
+use matrix_sdk::Room;
+use mime_guess::mime::{self, Mime};
+
+use std::io::Cursor;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+/// Default max size of an attachment, in bytes
+pub const DEFAULT_ATTACHMENT_SIZE_LIMIT: usize = 15 * 1024 * 1024;
+
+/// How long to wait for an attachment to download
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most redirects to follow when downloading an attachment
+const MAX_REDIRECTS: usize = 5;
+
+/// A file to send to a room
+#[derive(Clone)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: Mime,
+    pub data: Vec<u8>,
+}
+
+impl std::fmt::Debug for Attachment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Attachment")
+            .field("filename", &self.filename)
+            .field("content_type", &self.content_type)
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
+
+/// Check that an address is on the public internet.
+///
+/// Rejects loopback, private, link-local (including cloud metadata), shared and other special
+/// addresses, so URLs can't be used to reach the daemon's own network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64)
+                // IETF protocol assignments, 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking, 198.18.0.0/15
+                || (a == 198 && (b & 0xfe) == 18)
+                // Reserved, 240.0.0.0/4
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            if ip.is_unspecified() || ip.is_loopback() {
+                return false;
+            }
+            let segments = ip.segments();
+            let embedded = |high: u16, low: u16| {
+                let [a, b] = high.to_be_bytes();
+                let [c, d] = low.to_be_bytes();
+                is_public(IpAddr::V4(Ipv4Addr::new(a, b, c, d)))
+            };
+            match segments {
+                // IPv4-compatible, ::a.b.c.d
+                [0, 0, 0, 0, 0, 0, high, low] => return embedded(high, low),
+                // NAT64, 64:ff9b::/96
+                [0x64, 0xff9b, 0, 0, 0, 0, high, low] => return embedded(high, low),
+                // 6to4, 2002::/16
+                [0x2002, high, low, ..] => return embedded(high, low),
+                _ => {}
+            }
+            let first = segments[0];
+            !(ip.is_multicast()
+                // Local-use NAT64, 64:ff9b:1::/48
+                || segments[..3] == [0x64, 0xff9b, 1]
+                // Unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // Link local, fe80::/10
+                || (first & 0xffc0) == 0xfe80
+                // Site local, fec0::/10
+                || (first & 0xffc0) == 0xfec0)
+        }
+    }
+}
+
+/// GET a URL, if it and every redirect point to public http(s) addresses.
+///
+/// Each request is pinned to the address that was checked, so the host can't resolve to a
+/// different address when connecting.
+async fn fetch_public(url: &str) -> anyhow::Result<reqwest::Response> {
+    let mut url = url::Url::parse(url)?;
+    for _ in 0..=MAX_REDIRECTS {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(anyhow::anyhow!("Unsupported URL scheme: {}", url.scheme()));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("The URL has no host"))?
+            .to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let lookup = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((lookup, port)).await?.collect();
+        if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+            return Err(anyhow::anyhow!("Not allowed to download from {}", host));
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(DOWNLOAD_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve(lookup, addrs[0])
+            .build()?;
+        let response = client.get(url.clone()).send().await?;
+        if !response.status().is_redirection() {
+            return Ok(response.error_for_status()?);
+        }
+        let location = response
+            .headers()
+            .get("location")
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| anyhow::anyhow!("Redirect without a location"))?;
+        url = url.join(location)?;
+    }
+    Err(anyhow::anyhow!("Too many redirects"))
+}
+
+impl Attachment {
+    /// Create an attachment, detecting the MIME type.
+    ///
+    /// The contents are checked first, then the filename, and then the given content type.
+    pub fn new(filename: &str, data: Vec<u8>, content_type: Option<&str>) -> Self {
+        let content_type = infer::get(&data)
+            .and_then(|kind| kind.mime_type().parse().ok())
+            .or_else(|| mime_guess::from_path(filename).first())
+            .or_else(|| {
+                content_type
+                    .and_then(|c| c.parse::<Mime>().ok())
+                    // These are what HTTP clients send by default, they don't describe the file
+                    .filter(|c| {
+                        c.essence_str() != "application/x-www-form-urlencoded"
+                            && c.essence_str() != "application/octet-stream"
+                    })
+            })
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+        Attachment {
+            filename: filename.to_string(),
+            content_type,
+            data,
+        }
+    }
+
+    /// Download an attachment from a URL.
+    ///
+    /// Only public http(s) addresses can be downloaded from, see `fetch_public`.
+    /// Fails if the download takes too long or the file is larger than `size_limit`.
+    pub async fn download(
+        url: &str,
+        filename: Option<&str>,
+        size_limit: usize,
+    ) -> anyhow::Result<Self> {
+        let mut response = fetch_public(url).await?;
+        if response
+            .content_length()
+            .is_some_and(|length| length > size_limit as u64)
+        {
+            return Err(anyhow::anyhow!("Attachment is too large"));
+        }
+        let content_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|c| c.to_str().ok())
+            .map(String::from);
+
+        // The content length may be missing or wrong, so check as we go
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            data.extend_from_slice(&chunk);
+            if data.len() > size_limit {
+                return Err(anyhow::anyhow!("Attachment is too large"));
+            }
+        }
+
+        // Default to the last part of the URL path for the filename
+        let filename = filename.map(String::from).unwrap_or_else(|| {
+            url::Url::parse(url)
+                .ok()
+                .and_then(|url| {
+                    url.path_segments()
+                        .and_then(|mut segments| segments.next_back().map(String::from))
+                })
+                .filter(|name| !name.is_empty())
+                .unwrap_or("attachment".to_string())
+        });
+        Ok(Attachment::new(&filename, data, content_type.as_deref()))
+    }
+
+    /// Upload the attachment to the media repo and build the message content for it.
+    ///
+    /// The file is encrypted first if the room is encrypted.
+    pub async fn upload(&self, room: &Room) -> anyhow::Result<RoomMessageEventContent> {
+        let client = room.client();
+        let source = if room.is_encrypted().await.unwrap_or(false) {
+            let mut cursor = Cursor::new(&self.data);
+            let file = client
+                .prepare_encrypted_file(&self.content_type, &mut cursor)
+                .await?;
+            MediaSource::Encrypted(Box::new(file))
+        } else {
+            let response = client
+                .media()
+                .upload(&self.content_type, self.data.clone())
+                .await?;
+            MediaSource::Plain(response.content_uri)
+        };
+
+        let mimetype = Some(self.content_type.essence_str().to_string());
+        let size = UInt::new(self.data.len() as u64);
+        let body = self.filename.clone();
+        let msgtype = match self.content_type.type_() {
+            mime::IMAGE => {
+                let mut info = ImageInfo::new();
+                info.mimetype = mimetype;
+                info.size = size;
+                MessageType::Image(ImageMessageEventContent::new(body, source).info(Box::new(info)))
+            }
+            mime::AUDIO => {
+                let mut info = AudioInfo::new();
+                info.mimetype = mimetype;
+                info.size = size;
+                MessageType::Audio(AudioMessageEventContent::new(body, source).info(Box::new(info)))
+            }
+            mime::VIDEO => {
+                let mut info = VideoInfo::new();
+                info.mimetype = mimetype;
+                info.size = size;
+                MessageType::Video(VideoMessageEventContent::new(body, source).info(Box::new(info)))
+            }
+            _ => {
+                let mut info = FileInfo::new();
+                info.mimetype = mimetype;
+                info.size = size;
+                MessageType::File(
+                    FileMessageEventContent::new(body, source)
+                        .filename(self.filename.clone())
+                        .info(Box::new(info)),
+                )
+            }
+        };
+        Ok(RoomMessageEventContent::new(msgtype))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn public_addresses() {
+        assert!(public("1.1.1.1"));
+        assert!(public("93.184.216.34"));
+        assert!(public("2606:4700:4700::1111"));
+        assert!(public("::ffff:1.1.1.1"));
+        assert!(public("64:ff9b::101:101"));
+        assert!(public("2002:101:101::1"));
+    }
+
+    #[test]
+    fn ipv4_special_ranges() {
+        for ip in [
+            "0.0.0.0",
+            "0.1.2.3",
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "100.127.255.255",
+            "192.0.0.1",
+            "192.0.2.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "198.51.100.1",
+            "203.0.113.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!public(ip), "{} should not be public", ip);
+        }
+        // Just outside the ranges
+        assert!(public("100.128.0.1"));
+        assert!(public("198.20.0.1"));
+        assert!(public("192.0.1.1"));
+    }
+
+    #[test]
+    fn ipv6_special_ranges() {
+        for ip in [
+            "::",
+            "::1",
+            "ff02::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "fec0::1",
+            "64:ff9b:1::a00:1",
+        ] {
+            assert!(!public(ip), "{} should not be public", ip);
+        }
+    }
+
+    #[test]
+    fn embedded_ipv4() {
+        for ip in [
+            // IPv4-mapped
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            // IPv4-compatible
+            "::127.0.0.1",
+            "::10.0.0.1",
+            // NAT64
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            // 6to4
+            "2002:7f00:1::1",
+            "2002:c0a8:101::1",
+        ] {
+            assert!(!public(ip), "{} should not be public", ip);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use crate::token::AuthToken;
+use crate::webhooks::Template;
 use headjack::Bot;
 /// Common config options for pok'em
 use lazy_static::lazy_static;
@@ -24,6 +26,27 @@ pub struct DaemonConfig {
     /// Port to bind on.
     /// Will default to 80
     pub port: Option<u16>,
+    /// Max size of an attachment in bytes, for both uploads and downloaded URLs.
+    /// Defaults to 15MB
+    pub attachment_size_limit: Option<usize>,
+    /// Download attachment URLs and upload them to Matrix.
+    /// If disabled, attachment URLs are sent as links.
+    /// Defaults to true
+    pub fetch_attachments: Option<bool>,
+    /// How long to remember a poke group after its last poke, e.g. "24h".
+    /// Defaults to 24h
+    pub group_expiry: Option<String>,
+    /// Secret to verify signed webhooks, e.g. from GitHub, for rooms without their own secret.
+    pub webhook_secret: Option<String>,
+    /// How long to keep retrying a poke that failed to send, e.g. "24h".
+    /// Defaults to 24h
+    pub queue_max_age: Option<String>,
+    /// Named templates to build pokes from JSON webhooks.
+    /// Selected with the "template" query parameter or the "X-Template" header
+    pub templates: Option<HashMap<String, Template>>,
+    /// Token for the operator's admin API under /_pokem/admin/.
+    /// The admin API is disabled if it isn't set
+    pub admin_token: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -81,5 +104,12 @@ lazy_static! {
 #[derive(Clone, Debug, Default)]
 pub struct RoomConfig {
     pub block: bool,
-    pub auth: Option<String>,
+    /// Tokens allowed to poke the room, saved separately with `save_tokens`
+    pub tokens: Vec<AuthToken>,
+    /// Secret to verify signed webhooks and requests
+    pub secret: Option<String>,
+    /// Require pokes to be signed with the secret
+    pub signing: bool,
+    /// The config couldn't be read, so authentication fails until it can
+    pub unavailable: bool,
 }
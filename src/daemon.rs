@@ -1,62 +1,207 @@
 /// Run Pok'em as a daemon
+use crate::admin::{admin_request, ADMIN_PREFIX};
+use crate::attachment::{Attachment, DEFAULT_ATTACHMENT_SIZE_LIMIT};
 use crate::config::*;
+use crate::group::DEFAULT_GROUP_EXPIRY;
+use crate::outbox::{self, QueuedPoke, DEFAULT_QUEUE_MAX_AGE, MAX_DELAY};
+use crate::reminder::{self, list_reminders, remind_command};
+use crate::signature;
+use crate::subscribe::*;
+use crate::token::{save_tokens, token_command, AuthToken};
 use crate::utils::*;
+use crate::webhooks::{Webhook, WebhookPoke};
 
-use anyhow::Context;
+use base64::engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use base64::Engine;
 use clap::error::Result;
-use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::events::room::message::{
+    OriginalSyncRoomMessageEvent, RoomMessageEventContent,
+};
 
 use matrix_sdk::ruma::events::tag::TagInfo;
+use matrix_sdk::ruma::EventId;
 use matrix_sdk::Room;
 
 use serde::Deserialize;
 use tokio::sync::RwLock;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
+use http_body_util::combinators::BoxBody;
 use http_body_util::BodyExt;
 use http_body_util::Full;
+use http_body_util::{LengthLimitError, Limited};
 use hyper::body::Bytes;
 
+use hyper::header::HeaderValue;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
+use hyper::HeaderMap;
 use hyper::StatusCode;
-use hyper::{Request, Response};
+use hyper::{Request, Response, Uri};
 use hyper_util::rt::TokioIo;
 use tokio::net::TcpListener;
 
-#[derive(Debug, Clone, Deserialize)]
+/// Room for the form fields around an attachment in a request body
+const BODY_OVERHEAD: usize = 64 * 1024;
+
+/// How long a request waits for retries before the poke is left to the outbox
+const INLINE_RETRY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A request to poke a room.
+///
+/// The field names match ntfy's JSON publishing format, so ntfy publishers can send the same
+/// JSON body to the root of the server.
+#[derive(Debug, Clone, Default, Deserialize)]
 struct PokeRequest {
     topic: String,
     title: Option<String>,
+    #[serde(default)]
     message: String,
     priority: Option<u8>,
     tags: Option<Vec<String>>,
+    /// URL to open when the notification is clicked
+    click: Option<String>,
+    /// Action buttons, rendered as links in the message
+    actions: Option<Vec<PokeAction>>,
+    /// URL of an attachment
+    attach: Option<String>,
+    /// Filename of the attachment
+    filename: Option<String>,
+    /// Delay the message, e.g. "30m", "tomorrow, 10am" or a unix timestamp
+    #[serde(alias = "at", alias = "in")]
+    delay: Option<String>,
+    /// Whether to render the message as markdown
+    markdown: Option<bool>,
+    /// Event ID of an earlier message to edit
+    replaces: Option<String>,
+    /// Event ID of the thread root to send the message in
+    thread: Option<String>,
+    /// Event ID of an earlier message to reply to
+    reply_to: Option<String>,
+    /// Correlation key, pokes with the same key are sent in the same thread
+    group: Option<String>,
+    /// Respond as soon as the poke is saved to the outbox, instead of waiting for it to send
+    queue: Option<bool>,
+    /// Notification icon. Matrix has no per-message icons, so it is accepted and ignored
+    #[allow(dead_code)]
+    icon: Option<String>,
+    /// File uploaded in the request body
+    #[serde(skip)]
+    attachment: Option<Attachment>,
+    /// The request was verified without a token, e.g. a signed webhook
+    #[serde(skip)]
+    authenticated: bool,
+}
+
+/// An ntfy action button
+#[derive(Debug, Clone, Deserialize)]
+struct PokeAction {
+    /// "view", "http" or "broadcast"
+    action: String,
+    label: String,
+    url: Option<String>,
+    method: Option<String>,
 }
 
 impl PokeRequest {
     /// Try to deserialize the request from JSON, otherwise build it from headers and body.
-    pub async fn from_request(request: Request<hyper::body::Incoming>) -> anyhow::Result<Self> {
-        // Try JSON deserialization
-        let headers = request.headers().clone();
-        let uri = request.uri().clone();
+    /// If a template is selected, the poke is rendered from the JSON body instead.
+    ///
+    /// The body is treated as a file if it is a multipart form, if a filename is given, or if
+    /// it isn't valid UTF-8.
+    pub async fn from_request(
+        headers: &HeaderMap,
+        uri: &Uri,
+        body_bytes: Bytes,
+    ) -> anyhow::Result<Self> {
+        let mut query_params = get_query_params(uri);
+        let content_type = headers
+            .get("content-type")
+            .and_then(|c| c.to_str().ok())
+            .map(String::from);
+
+        // Render the poke from the JSON body with a template from the config
+        if let Some(name) = query_params.get("template").cloned().or_else(|| {
+            headers
+                .get("x-template")
+                .or_else(|| headers.get("template"))
+                .and_then(|template| template.to_str().ok().map(String::from))
+        }) {
+            let template = GLOBAL_CONFIG
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .daemon
+                .as_ref()
+                .and_then(|d| d.templates.as_ref())
+                .and_then(|templates| templates.get(&name).cloned())
+                .ok_or(anyhow::anyhow!("Unknown template: {}", name))?;
+            return Ok(PokeRequest::from_webhook(
+                uri.path().trim_start_matches('/'),
+                template.render(&body_bytes)?,
+            ));
+        }
+
+        let filename = query_params.get("filename").cloned().or_else(|| {
+            headers
+                .get("x-filename")
+                .or_else(|| headers.get("filename"))
+                .or_else(|| headers.get("file"))
+                .or_else(|| headers.get("f"))
+                .and_then(|filename| filename.to_str().ok().map(String::from))
+        });
+        let multipart_boundary = content_type
+            .as_deref()
+            .and_then(|c| multer::parse_boundary(c).ok());
 
-        let body_bytes = request.collect().await?.to_bytes();
-        let body_str =
-            String::from_utf8(body_bytes.to_vec()).with_context(|| "error while decoding UTF-8")?;
-        let Ok(poke_request) = serde_json::from_str::<PokeRequest>(&body_str) else {
+        let mut attachment = None;
+        let body_str = if let Some(boundary) = multipart_boundary {
+            // The form fields are used like query parameters, and any file is the attachment
+            let stream = tokio_stream::once(Ok::<Bytes, Infallible>(body_bytes));
+            let mut multipart = multer::Multipart::new(stream, boundary);
+            while let Some(field) = multipart.next_field().await? {
+                let name = field.name().unwrap_or_default().to_lowercase();
+                if let Some(file_name) = field.file_name().map(String::from) {
+                    let field_type = field.content_type().map(|c| c.to_string());
+                    let data = field.bytes().await?.to_vec();
+                    let file_name = filename.clone().unwrap_or(file_name);
+                    attachment = Some(Attachment::new(&file_name, data, field_type.as_deref()));
+                } else {
+                    let value = field.text().await?;
+                    query_params.entry(name).or_insert(value);
+                }
+            }
+            String::new()
+        } else {
+            match String::from_utf8(body_bytes.to_vec()) {
+                Ok(body_str) if filename.is_none() => body_str,
+                _ => {
+                    let file_name = filename.clone().unwrap_or("attachment".to_string());
+                    attachment = Some(Attachment::new(
+                        &file_name,
+                        body_bytes.to_vec(),
+                        content_type.as_deref(),
+                    ));
+                    String::new()
+                }
+            }
+        };
+
+        // Try JSON deserialization
+        let json_request = if attachment.is_none() {
+            serde_json::from_str::<PokeRequest>(&body_str).ok()
+        } else {
+            None
+        };
+        let Some(poke_request) = json_request else {
             // Build from headers and body
-            let query_params: HashMap<String, String> = uri
-                .query()
-                .map(|v| {
-                    url::form_urlencoded::parse(v.as_bytes())
-                        .map(|(a, b)| (a.to_lowercase(), b.to_string()))
-                        .collect()
-                })
-                .unwrap_or_default();
             return Ok(PokeRequest {
                 // The uri without the leading / will be the room id
                 topic: uri.path().trim_start_matches('/').to_string(),
@@ -89,18 +234,7 @@ impl PokeRequest {
                             .or_else(|| headers.get("prio"))
                             .or_else(|| headers.get("p"))
                             .and_then(|priority_header| {
-                                priority_header.to_str().ok().map(|header_str| {
-                                    header_str.parse().unwrap_or_else(|_| {
-                                        match &header_str.to_lowercase()[..] {
-                                            "min" => 1,
-                                            "low" => 2,
-                                            "default" => 3,
-                                            "high" => 4,
-                                            "urgent" | "max" => 5,
-                                            _ => 3,
-                                        }
-                                    })
-                                })
+                                priority_header.to_str().ok().map(parse_priority)
                             })
                     }),
                 tags: query_params
@@ -115,12 +249,142 @@ impl PokeRequest {
                             .and_then(|tags| tags.to_str().ok().map(String::from))
                     })
                     .map(|tags_str| tags_str.split(',').map(String::from).collect()),
+                click: query_params.get("click").cloned().or_else(|| {
+                    headers
+                        .get("x-click")
+                        .or_else(|| headers.get("click"))
+                        .and_then(|click| click.to_str().ok().map(String::from))
+                }),
+                actions: None,
+                attach: query_params.get("attach").cloned().or_else(|| {
+                    headers
+                        .get("x-attach")
+                        .or_else(|| headers.get("attach"))
+                        .or_else(|| headers.get("a"))
+                        .and_then(|attach| attach.to_str().ok().map(String::from))
+                }),
+                filename,
+                delay: ["delay", "at", "in"]
+                    .iter()
+                    .find_map(|name| query_params.get(*name).cloned())
+                    .or_else(|| {
+                        ["x-delay", "delay", "x-at", "at", "x-in", "in"]
+                            .iter()
+                            .find_map(|name| headers.get(*name))
+                            .and_then(|delay| delay.to_str().ok().map(String::from))
+                    }),
+                markdown: query_params
+                    .get("markdown")
+                    .cloned()
+                    .or_else(|| {
+                        headers
+                            .get("x-markdown")
+                            .or_else(|| headers.get("markdown"))
+                            .or_else(|| headers.get("md"))
+                            .and_then(|md| md.to_str().ok().map(String::from))
+                    })
+                    .map(|md| matches!(&md.to_lowercase()[..], "yes" | "true" | "1")),
+                replaces: query_params.get("replaces").cloned().or_else(|| {
+                    headers
+                        .get("x-replaces")
+                        .or_else(|| headers.get("replaces"))
+                        .and_then(|replaces| replaces.to_str().ok().map(String::from))
+                }),
+                thread: query_params.get("thread").cloned().or_else(|| {
+                    headers
+                        .get("x-thread")
+                        .or_else(|| headers.get("thread"))
+                        .and_then(|thread| thread.to_str().ok().map(String::from))
+                }),
+                reply_to: query_params.get("reply_to").cloned().or_else(|| {
+                    headers
+                        .get("x-reply-to")
+                        .or_else(|| headers.get("reply-to"))
+                        .or_else(|| headers.get("in-reply-to"))
+                        .and_then(|reply_to| reply_to.to_str().ok().map(String::from))
+                }),
+                group: query_params.get("group").cloned().or_else(|| {
+                    headers
+                        .get("x-group")
+                        .or_else(|| headers.get("group"))
+                        .and_then(|group| group.to_str().ok().map(String::from))
+                }),
+                queue: query_params
+                    .get("queue")
+                    .cloned()
+                    .or_else(|| {
+                        headers
+                            .get("x-queue")
+                            .or_else(|| headers.get("queue"))
+                            .and_then(|queue| queue.to_str().ok().map(String::from))
+                    })
+                    .map(|queue| matches!(&queue.to_lowercase()[..], "yes" | "true" | "1")),
+                icon: None,
+                attachment,
+                authenticated: false,
             });
         };
         Ok(poke_request)
     }
 }
 
+impl PokeRequest {
+    fn from_webhook(room: &str, poke: WebhookPoke) -> Self {
+        PokeRequest {
+            topic: room.to_string(),
+            title: poke.title,
+            message: poke.message,
+            priority: poke.priority,
+            tags: poke.tags,
+            click: poke.click,
+            group: poke.group,
+            ..Default::default()
+        }
+    }
+}
+
+/// Get the earlier message that the poke edits, threads under or replies to
+fn get_relation(poke_request: &PokeRequest) -> anyhow::Result<Option<MessageRelation>> {
+    let relations = [
+        (
+            &poke_request.replaces,
+            MessageRelation::Replaces as fn(_) -> _,
+        ),
+        (&poke_request.thread, MessageRelation::Thread),
+        (&poke_request.reply_to, MessageRelation::Reply),
+    ];
+    let mut relations = relations
+        .into_iter()
+        .filter_map(|(event_id, relation)| event_id.as_deref().map(|id| (id, relation)));
+    let Some((event_id, relation)) = relations.next() else {
+        return Ok(None);
+    };
+    if relations.next().is_some() {
+        return Err(anyhow::anyhow!(
+            "Only one of Replaces, Thread or Reply-To can be used"
+        ));
+    }
+    if poke_request.group.is_some() {
+        return Err(anyhow::anyhow!(
+            "Group can't be used with Replaces, Thread or Reply-To"
+        ));
+    }
+    let event_id = EventId::parse(event_id)
+        .map_err(|e| anyhow::anyhow!("Invalid event ID {}: {}", event_id, e))?;
+    Ok(Some(relation(event_id)))
+}
+
+/// Get the query parameters from the uri, with lowercase keys
+pub(crate) fn get_query_params(uri: &hyper::Uri) -> HashMap<String, String> {
+    uri.query()
+        .map(|v| {
+            url::form_urlencoded::parse(v.as_bytes())
+                .map(|(a, b)| (a.to_lowercase(), b.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 /// Run in daemon mode
 /// This binds to a port and listens for incoming requests, and sends them to the Matrix room
 pub async fn daemon(
@@ -156,6 +420,43 @@ pub async fn daemon(
     let bot = connect(matrix_config).await?;
     GLOBAL_BOT.lock().unwrap().replace(bot.clone());
 
+    // Forward room messages to the HTTP subscriptions
+    register_message_handler(&bot);
+
+    // Redact the commands that set a token or secret, so they don't stay in the room
+    bot.client().add_event_handler(
+        |event: OriginalSyncRoomMessageEvent, room: Room| async move {
+            if !is_secret_command(event.content.body()) {
+                return;
+            }
+            if let Err(e) = room
+                .redact(&event.event_id, Some("Contains a secret"), None)
+                .await
+            {
+                error!(
+                    "Failed to redact the command in {}: {:?}",
+                    room.room_id(),
+                    e
+                );
+            }
+        },
+    );
+
+    // Retry the pokes that failed to send
+    outbox::open(&bot.state_dir())?;
+    reminder::open()?;
+    let group_expiry = config
+        .as_ref()
+        .and_then(|d| d.group_expiry.as_deref())
+        .and_then(parse_duration)
+        .unwrap_or(DEFAULT_GROUP_EXPIRY);
+    let queue_max_age = config
+        .as_ref()
+        .and_then(|d| d.queue_max_age.as_deref())
+        .and_then(parse_duration)
+        .unwrap_or(DEFAULT_QUEUE_MAX_AGE);
+    tokio::task::spawn(outbox::run(bot.clone(), group_expiry, queue_max_age));
+
     // Register an info command to echo the room info
     bot.register_text_command(
         "info",
@@ -194,6 +495,8 @@ pub async fn daemon(
                 &reqwest::header::HeaderMap::new(),
                 &message,
                 false,
+                None,
+                None,
             )
             .await
             {
@@ -263,12 +566,68 @@ pub async fn daemon(
     // Register command to set variables
     bot.register_text_command(
         "set",
-        Some("<block|auth> <on|off|token>".to_string()),
+        Some("<block|auth|secret|signing> <on|off|token>".to_string()),
         Some("Configure settings for Pok'em in this room".to_string()),
         set_command,
     )
     .await;
 
+    // Manage the authentication tokens for the room
+    bot.register_text_command(
+        "token",
+        Some("<create|list|revoke> [name]".to_string()),
+        Some("Manage the authentication tokens for this room".to_string()),
+        |sender, msg, room| async move {
+            let command = msg.trim_start_matches(&get_command_prefix());
+            let args: Vec<&str> = command.split_whitespace().skip(1).collect();
+            let response = token_command(&room, &sender, &args).await;
+            if can_message_room(&room).await {
+                room.send(RoomMessageEventContent::text_markdown(response))
+                    .await
+                    .expect("Failed to send message");
+            }
+            Ok(())
+        },
+    )
+    .await;
+
+    // Set reminders from the room
+    bot.register_text_command(
+        "remind",
+        Some("<in|at|every> <time> <message> | cancel <id>".to_string()),
+        Some("Set a reminder for this room".to_string()),
+        |_, msg, room| async move {
+            let command = msg.trim_start_matches(&get_command_prefix());
+            let args: Vec<&str> = command.split_whitespace().skip(1).collect();
+            let response = remind_command(room.room_id(), &args).await;
+            if can_message_room(&room).await {
+                room.send(RoomMessageEventContent::text_markdown(response))
+                    .await
+                    .expect("Failed to send message");
+            }
+            Ok(())
+        },
+    )
+    .await;
+
+    // List the reminders in the room
+    bot.register_text_command(
+        "reminders",
+        None,
+        Some("List the reminders for this room".to_string()),
+        |_, _, room| async move {
+            let response = list_reminders(room.room_id()).await;
+            if can_message_room(&room).await {
+                room.send(RoomMessageEventContent::text_markdown(response))
+                    .await
+                    .expect("Failed to send message");
+            }
+            Ok(())
+        },
+    )
+    .await;
+    tokio::task::spawn(reminder::run(bot.clone()));
+
     // Spawn a tokio task to continuously accept incoming connections
     let rooms = Arc::new(RwLock::new(rooms));
     tokio::task::spawn(async move {
@@ -291,7 +650,10 @@ pub async fn daemon(
             let cloned_rooms = rooms.clone();
             tokio::task::spawn(async move {
                 if let Err(err) = http1::Builder::new()
-                    .serve_connection(io, service_fn(|req| daemon_poke(req, cloned_rooms.clone())))
+                    .serve_connection(
+                        io,
+                        service_fn(|req| daemon_request(req, cloned_rooms.clone())),
+                    )
                     .await
                 {
                     eprintln!("Error serving connection: {:?}", err);
@@ -312,6 +674,15 @@ pub async fn daemon(
 /// Sets config options for the room
 async fn set_command(_: matrix_sdk::ruma::OwnedUserId, msg: String, room: Room) -> Result<(), ()> {
     let mut room_config = get_room_config(&room).await;
+    // Saving a partial config would remove the parts that couldn't be read
+    if room_config.unavailable {
+        room.send(RoomMessageEventContent::text_plain(
+            "ERROR: Failed to read the room's config",
+        ))
+        .await
+        .expect("Failed to send message");
+        return Ok(());
+    }
     let command = msg.trim_start_matches(&get_command_prefix());
     let key = command.split_whitespace().nth(1).unwrap_or_default();
     let value = command.split_whitespace().nth(2).unwrap_or_default();
@@ -345,25 +716,87 @@ async fn set_command(_: matrix_sdk::ruma::OwnedUserId, msg: String, room: Room)
             } else if value.to_lowercase() == "on" {
                 "Tried setting the Auth Token to 'on', that was probably an accident".to_string()
             } else if value.to_lowercase() == "off" {
-                room_config.auth = None;
-                "Auth Token removed".to_string()
+                match save_tokens(&room, Vec::new()).await {
+                    Ok(()) => "Auth Tokens removed".to_string(),
+                    Err(e) => {
+                        error!("Failed to save the tokens: {:?}", e);
+                        "ERROR: Failed to remove the Auth Tokens".to_string()
+                    }
+                }
+            } else {
+                // Replaces the token set the same way, named tokens are kept
+                let mut tokens = room_config.tokens.clone();
+                tokens.retain(|token| token.name != DEFAULT_TOKEN_NAME);
+                tokens.push(AuthToken::new(DEFAULT_TOKEN_NAME, value));
+                match save_tokens(&room, tokens).await {
+                    Ok(()) => format!("Auth Token set as `{}`", DEFAULT_TOKEN_NAME),
+                    Err(e) => {
+                        error!("Failed to save the tokens: {:?}", e);
+                        "ERROR: Failed to set the Auth Token".to_string()
+                    }
+                }
+            }
+        }
+        "signing" => {
+            // Require requests to be signed with the secret
+            if value.to_lowercase() == "on" {
+                if room_config.secret.is_none() {
+                    format!(
+                        "Set a secret first with `{}set secret <secret>`",
+                        get_command_prefix()
+                    )
+                } else {
+                    room_config.signing = true;
+                    "Requiring signed requests".to_string()
+                }
+            } else if value.to_lowercase() == "off" {
+                room_config.signing = false;
+                "Not requiring signed requests".to_string()
+            } else {
+                format!(
+                    "Signing must be on or off\n`{}set signing [on|off]`",
+                    get_command_prefix()
+                )
+            }
+        }
+        "secret" => {
+            // Set the secret used to verify signed webhooks
+            if value.is_empty() {
+                format!(
+                    "Secret cannot be empty\n`{}set secret [off|secret]`",
+                    get_command_prefix()
+                )
+            } else if value.to_lowercase() == "off" {
+                room_config.secret = None;
+                room_config.signing = false;
+                "Webhook Secret removed".to_string()
             } else {
-                room_config.auth = Some(value.to_string());
-                format!("Auth Token set to {}", value).to_string()
+                room_config.secret = Some(value.to_string());
+                "Webhook Secret set".to_string()
             }
         }
         _ => {
             let block_status = if room_config.block { "on" } else { "off" };
             format!(
                 "Usage:
-`{}set [block|auth] <on|off|token>`
-Current values:\n- block: {}{}",
+`{}set [block|auth|secret|signing] <on|off|token>`
+Current values:\n- block: {}{}{}{}",
                 get_command_prefix(),
                 block_status,
-                if let Some(token) = room_config.auth.clone() {
-                    format!("\n- Authentication Token: {}", token)
-                } else {
+                if room_config.tokens.is_empty() {
                     "".to_string()
+                } else {
+                    format!("\n- Authentication Tokens: {}", room_config.tokens.len())
+                },
+                if room_config.secret.is_some() {
+                    "\n- Webhook Secret: set"
+                } else {
+                    ""
+                },
+                if room_config.signing {
+                    "\n- Signing: required"
+                } else {
+                    ""
                 }
             )
         }
@@ -375,73 +808,446 @@ Current values:\n- block: {}{}",
     Ok(())
 }
 
-/// Poke the room from an http request
-async fn daemon_poke(
+/// Move the "auth" query parameter into the headers.
+///
+/// Like ntfy, it can be the base64 encoded Authorization header, e.g. "Bearer <token>", or it
+/// can be the token itself.
+fn auth_from_query(uri: &Uri, headers: &mut HeaderMap) {
+    let Some(auth) = get_query_params(uri).remove("auth") else {
+        return;
+    };
+    let auth = auth.trim().trim_end_matches('=');
+    let authorization = URL_SAFE_NO_PAD
+        .decode(auth)
+        .or_else(|_| STANDARD_NO_PAD.decode(auth))
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .filter(|decoded| {
+            let scheme = decoded.split(' ').next().unwrap_or_default().to_lowercase();
+            scheme == "bearer" || scheme == "basic"
+        });
+    match authorization.and_then(|value| HeaderValue::from_str(&value).ok()) {
+        Some(value) => {
+            headers.entry("authorization").or_insert(value);
+        }
+        None => {
+            if let Ok(value) = HeaderValue::from_str(auth) {
+                headers.entry("authentication").or_insert(value);
+            }
+        }
+    }
+}
+
+/// Route an http request to the right handler
+async fn daemon_request(
+    mut request: Request<hyper::body::Incoming>,
+    rooms: Arc<RwLock<Option<HashMap<String, String>>>>,
+) -> anyhow::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let path = request.uri().path().trim_start_matches('/').to_string();
+    // The admin token is never taken from the query, it would end up in logs
+    if let Some(admin_path) = path.strip_prefix(ADMIN_PREFIX) {
+        return admin_request(
+            request.method(),
+            request.uri(),
+            request.headers(),
+            admin_path,
+        )
+        .await;
+    }
+    let uri = request.uri().clone();
+    auth_from_query(&uri, request.headers_mut());
+    if request.method() == hyper::Method::GET {
+        if let Some((room_id, format)) = StreamFormat::from_path(&path) {
+            let query_params = get_query_params(request.uri());
+            return daemon_subscribe(request.headers(), room_id, format, &query_params, rooms)
+                .await;
+        }
+    }
+    let scheduled = match path.rsplit_once('/') {
+        Some((room_id, "scheduled")) => Some((room_id, None)),
+        Some((prefix, id)) => prefix
+            .strip_suffix("/scheduled")
+            .map(|room_id| (room_id, Some(id))),
+        None => None,
+    };
+    if let Some((room_id, id)) = scheduled {
+        let method = request.method();
+        if method == hyper::Method::GET || (method == hyper::Method::DELETE && id.is_some()) {
+            return daemon_scheduled(method, request.headers(), room_id, id, rooms).await;
+        }
+        // A scheduled poke can't be sent to, so don't treat it as a room
+        if id.is_some() {
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header("Allow", "GET, DELETE")
+                .body(Full::new(Bytes::from_static(b"Method not allowed")).boxed())
+                .unwrap());
+        }
+    }
+    if request.method() != hyper::Method::GET {
+        if let Some((room_id, webhook)) = Webhook::from_path(&path) {
+            return daemon_webhook(request, room_id.to_string(), webhook, rooms).await;
+        }
+    }
+    daemon_poke(request, rooms).await
+}
+
+/// Get the largest attachment that can be sent
+fn attachment_size_limit() -> usize {
+    GLOBAL_CONFIG
+        .lock()
+        .unwrap()
+        .as_ref()
+        .unwrap()
+        .daemon
+        .as_ref()
+        .and_then(|d| d.attachment_size_limit)
+        .unwrap_or(DEFAULT_ATTACHMENT_SIZE_LIMIT)
+}
+
+/// Read the request body, stopping once it is larger than an attachment can be.
+///
+/// Returns the error response if the body is too large.
+async fn read_body(
+    body: hyper::body::Incoming,
+) -> anyhow::Result<Result<Bytes, Response<BoxBody<Bytes, Infallible>>>> {
+    match Limited::new(body, attachment_size_limit() + BODY_OVERHEAD)
+        .collect()
+        .await
+    {
+        Ok(body) => Ok(Ok(body.to_bytes())),
+        Err(e) if e.is::<LengthLimitError>() => Ok(Err(Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(Full::new(Bytes::from_static(b"Request is too large")).boxed())
+            .unwrap())),
+        Err(e) => Err(anyhow::Error::msg(e)),
+    }
+}
+
+/// Poke the room from a webhook sent by another service
+async fn daemon_webhook(
     request: Request<hyper::body::Incoming>,
+    room_id: String,
+    webhook: Webhook,
+    rooms: Arc<RwLock<Option<HashMap<String, String>>>>,
+) -> anyhow::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let mut headers = request.headers().clone();
+    let body = match read_body(request.into_body()).await? {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+    let mut poke_request = match webhook.parse(&headers, &body) {
+        Ok(Some(poke)) => PokeRequest::from_webhook(&room_id, poke),
+        Ok(None) => {
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .body(Full::new(Bytes::from_static(b"Ignored")).boxed())
+                .unwrap());
+        }
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Full::new(Bytes::from(format!("Invalid webhook: {}", e))).boxed())
+                .unwrap());
+        }
+    };
+
+    if let Some(token) = webhook.auth_token(&headers) {
+        headers.insert("authentication", token);
+    }
+
+    // Check the signature against the secret of the room the poke will be sent to
+    if let Some(secret) = signing_secret(&room_id, poke_request.priority, rooms.clone()).await {
+        match webhook.verify_signature(&headers, &body, secret.secret()) {
+            // Only a valid signature with the room's own secret stands in for its tokens
+            Some(true) => poke_request.authenticated = matches!(secret, SigningSecret::Room(_)),
+            Some(false) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .body(Full::new(Bytes::from_static(b"Invalid signature")).boxed())
+                    .unwrap());
+            }
+            None => {}
+        }
+    }
+
+    send_poke_request(headers, poke_request, false, rooms).await
+}
+
+/// The secret to verify a signed request with
+enum SigningSecret {
+    /// The room's own secret, a valid signature stands in for the room's tokens
+    Room(String),
+    /// The daemon's webhook secret. It is shared by every room, so it only proves where a
+    /// webhook came from and the room's tokens are still needed
+    Daemon(String),
+}
+
+impl SigningSecret {
+    fn secret(&self) -> &str {
+        match self {
+            SigningSecret::Room(secret) | SigningSecret::Daemon(secret) => secret,
+        }
+    }
+}
+
+/// Get the secret to verify signed requests to a room, the room's own or the daemon's.
+///
+/// The room is resolved the same way as the poke, so the secret of an urgent room is used for
+/// urgent pokes.
+async fn signing_secret(
+    room_id: &str,
+    priority: Option<u8>,
     rooms: Arc<RwLock<Option<HashMap<String, String>>>>,
-) -> anyhow::Result<Response<Full<Bytes>>> {
-    let headers = request.headers().clone();
-    let is_get = request.method() == hyper::Method::GET;
-    let mut poke_request = PokeRequest::from_request(request).await?;
+) -> Option<SigningSecret> {
+    let (target_room, _) = resolve_room(
+        rooms.read().await.as_ref(),
+        &urlencoding::decode(room_id).map_or(room_id.to_string(), |room| room.to_string()),
+        is_urgent(priority),
+    );
+    let bot = GLOBAL_BOT.lock().unwrap().as_ref().unwrap().clone();
+    let room = get_room_from_name(&bot, &target_room).await?;
+    if let Some(secret) = get_room_config(&room).await.secret {
+        return Some(SigningSecret::Room(secret));
+    }
+    GLOBAL_CONFIG
+        .lock()
+        .unwrap()
+        .as_ref()
+        .unwrap()
+        .daemon
+        .as_ref()
+        .and_then(|d| d.webhook_secret.clone())
+        .map(SigningSecret::Daemon)
+}
 
+/// Find the room from the request path, and check the request's authentication token.
+///
+/// Rooms without tokens and restricted tokens are refused, these endpoints read from the room.
+/// Returns the error response if the room isn't found or the token is wrong.
+async fn find_authorized_room(
+    headers: &HeaderMap,
+    room_id: &str,
+    rooms: Arc<RwLock<Option<HashMap<String, String>>>>,
+) -> Result<Room, Response<BoxBody<Bytes, Infallible>>> {
     // The room_id may be URI encoded
-    let mut room_id = match urlencoding::decode(&poke_request.topic) {
+    let room_id = match urlencoding::decode(room_id) {
         Ok(room) => room.to_string(),
-        Err(_) => poke_request.topic,
+        Err(_) => room_id.to_string(),
+    };
+    // If the room is a room name in the config, we'll transform it to the room id
+    let room_id = match rooms.read().await.as_ref().and_then(|r| r.get(&room_id)) {
+        Some(room_id) => room_id.to_string(),
+        None => room_id,
     };
 
-    let urgent = poke_request.priority.is_some_and(|p| p > 3);
+    // Get a copy of the bot
+    let bot = GLOBAL_BOT.lock().unwrap().as_ref().unwrap().clone();
+    let Some(room) = get_room_from_name(&bot, &room_id).await else {
+        return Err(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::from_static(b"Room not found")).boxed())
+            .unwrap());
+    };
 
-    // Add title
-    if let Some(title) = poke_request.title {
-        poke_request.message = format!("**{title}**\n\n{}", poke_request.message);
+    let room_config = get_room_config(&room).await;
+    // Anyone could read a room without tokens, so it can't be read at all
+    if room_config.tokens.is_empty() {
+        return Err(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Full::new(Bytes::from_static(b"The room has no Authentication token")).boxed())
+            .unwrap());
+    }
+    match validate_authentication(room_config, headers, "") {
+        Ok((_, Some(token))) if token.scope.is_restricted() => Err(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Full::new(Bytes::from_static(b"The token is restricted")).boxed())
+            .unwrap()),
+        Ok(_) => Ok(room),
+        Err(_) => Err(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Full::new(Bytes::from_static(b"Incorrect Authentication Token")).boxed())
+            .unwrap()),
     }
+}
 
-    // Add emojis
-    if let Some(tags) = poke_request.tags {
-        let mut emojis_str = String::new();
-        let mut non_emojis = Vec::new();
-        for shortcode in tags {
-            if let Some(emoji) = emojis::get_by_shortcode(shortcode.as_str()) {
-                emojis_str.push_str(emoji.as_ref());
-            } else {
-                non_emojis.push(shortcode);
-            }
-        }
-        if !emojis_str.is_empty() {
-            poke_request.message = format!("{emojis_str} {}", poke_request.message);
+/// List the pokes waiting to be sent to a room, or get or cancel one of them
+async fn daemon_scheduled(
+    method: &hyper::Method,
+    headers: &HeaderMap,
+    room_id: &str,
+    id: Option<&str>,
+    rooms: Arc<RwLock<Option<HashMap<String, String>>>>,
+) -> anyhow::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let room = match find_authorized_room(headers, room_id, rooms).await {
+        Ok(room) => room,
+        Err(response) => return Ok(response),
+    };
+
+    // The pokes may have been sent to the room's alias or ID
+    let bot = GLOBAL_BOT.lock().unwrap().as_ref().unwrap().clone();
+    let mut pending = Vec::new();
+    for poke in outbox::pending()? {
+        if get_room_from_name(&bot, &poke.room_id)
+            .await
+            .is_some_and(|r| r.room_id() == room.room_id())
+        {
+            pending.push(poke);
         }
-        if !non_emojis.is_empty() {
-            poke_request.message =
-                format!("{}\nTags: {}", poke_request.message, non_emojis.join(", "));
+    }
+
+    let Some(id) = id else {
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(serde_json::to_string(&pending)?)).boxed())
+            .unwrap());
+    };
+    match pending.iter().find(|poke| poke.id.to_string() == id) {
+        Some(poke) if method == hyper::Method::GET => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(serde_json::to_string(poke)?)).boxed())
+            .unwrap()),
+        Some(poke) => {
+            outbox::cancel(poke.id)?;
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .body(Full::new(Bytes::from_static(b"OK")).boxed())
+                .unwrap())
         }
+        None => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::from_static(b"Scheduled poke not found")).boxed())
+            .unwrap()),
     }
+}
 
-    // If the room is a room name in the config, we'll transform it to the room id.
-    // If the message is urgent and <room_name>-urgent exists, it will got there, otherwise
-    // we mention the entire @room.
-    let mut mention_room = false;
-    room_id = match &rooms.read().await.as_ref().and_then(|r| {
-        if urgent {
-            r.get(&format!("{}-urgent", room_id)).or_else(|| {
-                // No urgent room found, pinging @room
-                mention_room = true;
-                r.get(&room_id)
-            })
-        } else {
-            r.get(&room_id)
+/// Subscribe to the messages in a room
+async fn daemon_subscribe(
+    headers: &HeaderMap,
+    room_id: &str,
+    format: StreamFormat,
+    query_params: &HashMap<String, String>,
+    rooms: Arc<RwLock<Option<HashMap<String, String>>>>,
+) -> anyhow::Result<Response<BoxBody<Bytes, Infallible>>> {
+    // Subscribing requires the same token as sending
+    let room = match find_authorized_room(headers, room_id, rooms).await {
+        Ok(room) => room,
+        Err(response) => return Ok(response),
+    };
+
+    let since = query_params.get("since").map(|since| Since::parse(since));
+    let is_poll = query_params
+        .get("poll")
+        .is_some_and(|poll| matches!(&poll.to_lowercase()[..], "1" | "yes" | "true"));
+    if is_poll {
+        // Polling defaults to every cached message
+        Ok(poll(room, format, since.unwrap_or(Since::All)))
+    } else {
+        Ok(subscribe(room, format, since))
+    }
+}
+
+/// Poke the room from an http request
+async fn daemon_poke(
+    request: Request<hyper::body::Incoming>,
+    rooms: Arc<RwLock<Option<HashMap<String, String>>>>,
+) -> anyhow::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let (parts, body) = request.into_parts();
+    let body = match read_body(body).await? {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+    let is_signed = signature::is_signed(&parts.headers);
+    let headers = if is_signed {
+        signature::signed_headers(&parts.headers)
+    } else {
+        parts.headers.clone()
+    };
+    let is_get = parts.method == hyper::Method::GET;
+    let mut poke_request = match PokeRequest::from_request(&headers, &parts.uri, body.clone()).await
+    {
+        Ok(poke_request) => poke_request,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Full::new(Bytes::from(format!("Invalid request: {}", e))).boxed())
+                .unwrap());
         }
-    }) {
-        Some(room_id) => room_id.to_string(),
-        _ => {
-            // No urgent room found, pinging @room
-            if urgent {
-                mention_room = true;
+    };
+
+    // A request signed with the room's own secret stands in for the room's tokens
+    if is_signed {
+        let secret =
+            signing_secret(&poke_request.topic, poke_request.priority, rooms.clone()).await;
+        let verified = match secret {
+            Some(SigningSecret::Room(secret)) => {
+                signature::verify(&parts.method, &parts.uri, &parts.headers, &body, &secret)
             }
-            room_id
+            _ => Err(anyhow::anyhow!(
+                "The room has no secret to verify signatures"
+            )),
+        };
+        if let Err(e) = verified {
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(Full::new(Bytes::from(e.to_string())).boxed())
+                .unwrap());
         }
+        poke_request.authenticated = true;
+    }
+    send_poke_request(headers, poke_request, is_get, rooms).await
+}
+
+/// Send a poke request to its room, or serve the WebUI for a GET request
+async fn send_poke_request(
+    mut headers: HeaderMap,
+    mut poke_request: PokeRequest,
+    is_get: bool,
+    rooms: Arc<RwLock<Option<HashMap<String, String>>>>,
+) -> anyhow::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let daemon_config = GLOBAL_CONFIG
+        .lock()
+        .unwrap()
+        .as_ref()
+        .unwrap()
+        .daemon
+        .clone();
+    let size_limit = attachment_size_limit();
+    let fetch_attachments = daemon_config
+        .as_ref()
+        .and_then(|d| d.fetch_attachments)
+        .unwrap_or(true);
+    let group_expiry = daemon_config
+        .as_ref()
+        .and_then(|d| d.group_expiry.as_deref())
+        .and_then(parse_duration)
+        .unwrap_or(DEFAULT_GROUP_EXPIRY);
+    if poke_request
+        .attachment
+        .as_ref()
+        .is_some_and(|a| a.data.len() > size_limit)
+    {
+        return Ok(Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(Full::new(Bytes::from_static(b"Attachment is too large")).boxed())
+            .unwrap());
+    }
+
+    // The room_id may be URI encoded
+    let room_id = match urlencoding::decode(&poke_request.topic) {
+        Ok(room) => room.to_string(),
+        Err(_) => poke_request.topic.clone(),
     };
 
+    // If the room is a room name in the config, we'll transform it to the room id.
+    let (room_id, mention_room) = resolve_room(
+        rooms.read().await.as_ref(),
+        &room_id,
+        is_urgent(poke_request.priority),
+    );
+
     // If it's a GET request, we'll serve a WebUI
     if is_get {
         // Create the webpage with the room id filled in
@@ -541,31 +1347,239 @@ async fn daemon_poke(
         .to_string();
         return Ok(Response::builder()
             .status(StatusCode::OK)
-            .body(Full::new(Bytes::from(page)))
+            .body(Full::new(Bytes::from(page)).boxed())
+            .unwrap());
+    }
+
+    if room_id.is_empty() {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Full::new(Bytes::from_static(b"No room specified")).boxed())
             .unwrap());
     }
 
     // Get a copy of the bot
     let bot = GLOBAL_BOT.lock().unwrap().as_ref().unwrap().clone();
 
-    if let Err(e) = ping_room(
-        &bot,
-        &room_id,
-        &headers,
-        &poke_request.message,
-        mention_room,
-    )
-    .await
-    {
-        error!("Failed to send message: {:?}", e);
+    // Check the authentication token before the poke is sent or queued
+    let Some(room) = get_room_from_name(&bot, &room_id).await else {
         return Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
-            .body(Full::new(Bytes::from_static(b"Failed to send message")))
+            .body(Full::new(Bytes::from_static(b"Room not found")).boxed())
             .unwrap());
+    };
+    let mut token = None;
+    let room_config = get_room_config(&room).await;
+    if room_config.signing && !poke_request.authenticated {
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Full::new(Bytes::from_static(b"The room requires signed requests")).boxed())
+            .unwrap());
+    }
+    if !poke_request.authenticated {
+        match validate_authentication(room_config, &headers, &poke_request.message) {
+            Ok((message, matched)) => {
+                poke_request.message = message;
+                token = matched;
+            }
+            Err(e) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .body(Full::new(Bytes::from(e.to_string())).boxed())
+                    .unwrap());
+            }
+        }
     }
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .body(Full::new(Bytes::from_static(b"OK")))
-        .unwrap())
+    // Add the title and tags
+    poke_request.message = compose_message(
+        &poke_request.message,
+        poke_request.title.as_deref(),
+        poke_request.tags.as_deref(),
+    );
+
+    // Add the click URL, action buttons and attachment as links, Matrix has no buttons
+    let mut links = Vec::new();
+    if let Some(click) = &poke_request.click {
+        links.push(format!("[Open]({click})"));
+    }
+    for action in poke_request.actions.iter().flatten() {
+        match (action.action.as_str(), &action.url) {
+            ("view", Some(url)) => links.push(format!("[{}]({url})", action.label)),
+            ("http", Some(url)) => links.push(format!(
+                "{} ({} {url})",
+                action.label,
+                action.method.as_deref().unwrap_or("POST")
+            )),
+            _ => debug!("Ignoring unsupported action: {:?}", action),
+        }
+    }
+    if let Some(attach) = &poke_request.attach {
+        // Download the attachment so it shows inline, falling back to a link
+        let mut downloaded = None;
+        if fetch_attachments && !is_get && poke_request.attachment.is_none() {
+            match Attachment::download(attach, poke_request.filename.as_deref(), size_limit).await {
+                Ok(attachment) => downloaded = Some(attachment),
+                Err(e) => error!("Failed to download attachment {}: {:?}", attach, e),
+            }
+        }
+        if downloaded.is_some() {
+            poke_request.attachment = downloaded;
+        } else {
+            let name = poke_request.filename.as_deref().unwrap_or("Attachment");
+            links.push(format!("[{name}]({attach})"));
+        }
+    }
+    if !links.is_empty() {
+        poke_request.message = format!("{}\n\n{}", poke_request.message, links.join(" | "));
+    }
+
+    // The markdown setting overrides the format header
+    if let Some(markdown) = poke_request.markdown {
+        let format = if markdown { "markdown" } else { "plain" };
+        headers.insert("format", HeaderValue::from_static(format));
+    }
+
+    let relation = match get_relation(&poke_request) {
+        Ok(relation) => relation,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Full::new(Bytes::from(e.to_string())).boxed())
+                .unwrap());
+        }
+    };
+
+    // Check the poke against the token's restrictions
+    let (relation, group) = match &token {
+        Some(token) => match token.authorize(poke_request.priority, mention_room, relation) {
+            // The token's thread takes the place of the group's thread
+            Ok(relation) if token.scope.thread.is_some() => (relation, None),
+            Ok(relation) => (relation, poke_request.group),
+            Err(e) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Full::new(Bytes::from(e.to_string())).boxed())
+                    .unwrap());
+            }
+        },
+        None => (relation, poke_request.group),
+    };
+
+    let poke = QueuedPoke {
+        room_id,
+        message: poke_request.message,
+        headers: QueuedPoke::headers_from(&headers),
+        mention_room,
+        relation,
+        group,
+        attachment: poke_request.attachment,
+        rate_key: token.as_ref().and_then(|token| token.rate_key()),
+    };
+
+    // Delayed messages are saved to the outbox, so they are sent even if the daemon restarts
+    if let Some(delay) = &poke_request.delay {
+        let delay = match parse_delay(delay) {
+            Ok(delay) if delay > MAX_DELAY => {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(
+                        Full::new(Bytes::from_static(
+                            b"Pokes can't be scheduled more than 3 days ahead",
+                        ))
+                        .boxed(),
+                    )
+                    .unwrap());
+            }
+            Ok(delay) => delay,
+            Err(e) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Full::new(Bytes::from(e.to_string())).boxed())
+                    .unwrap());
+            }
+        };
+        return Ok(queue_poke(&poke, SystemTime::now() + delay, 0));
+    }
+
+    // Let the outbox send it
+    if poke_request.queue == Some(true) {
+        return Ok(queue_poke(&poke, SystemTime::now(), 0));
+    }
+
+    // Retry briefly before answering, so short hiccups don't turn into a queued poke
+    let deadline = Instant::now() + INLINE_RETRY_TIMEOUT;
+    let mut poke = poke;
+    let mut attempts = 1;
+    let mut result = poke.send(&bot, group_expiry).await;
+    while let Err(e) = &result {
+        let Some(retryable) = e.downcast_ref::<RetryableError>() else {
+            break;
+        };
+        let wait = retryable
+            .retry_after
+            .unwrap_or(Duration::from_secs(1 << attempts.min(4)));
+        if Instant::now() + wait > deadline {
+            break;
+        }
+        warn!("Failed to send message, retrying in {:?}: {:?}", wait, e);
+        // Only the message is retried if the attachment was already sent
+        if retryable.attachment_sent {
+            poke.attachment = None;
+        }
+        tokio::time::sleep(wait).await;
+        attempts += 1;
+        result = poke.send(&bot, group_expiry).await;
+    }
+
+    match result {
+        // Respond with the event ID so the message can be edited or replied to later
+        Ok(event_id) => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .body(
+                Full::new(Bytes::from(
+                    event_id.map_or("OK".to_string(), |id| id.to_string()),
+                ))
+                .boxed(),
+            )
+            .unwrap()),
+        Err(e) => {
+            error!("Failed to send message: {:?}", e);
+            // Save it to try again later if the homeserver is down or rate limiting us
+            if let Some(retryable) = e.downcast_ref::<RetryableError>() {
+                let send_at =
+                    SystemTime::now() + retryable.retry_after.unwrap_or(outbox::backoff(1));
+                // Only the message is retried if the attachment was already sent
+                if retryable.attachment_sent {
+                    poke.attachment = None;
+                }
+                return Ok(queue_poke(&poke, send_at, attempts));
+            }
+            Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Full::new(Bytes::from_static(b"Failed to send message")).boxed())
+                .unwrap())
+        }
+    }
+}
+
+/// Save the poke to the outbox, and respond with its queue ID
+fn queue_poke(
+    poke: &QueuedPoke,
+    send_at: SystemTime,
+    attempts: u32,
+) -> Response<BoxBody<Bytes, Infallible>> {
+    match outbox::enqueue(poke, send_at, attempts) {
+        Ok(id) => Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .body(Full::new(Bytes::from(id.to_string())).boxed())
+            .unwrap(),
+        Err(e) => {
+            error!("Failed to queue message: {:?}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Full::new(Bytes::from_static(b"Failed to queue message")).boxed())
+                .unwrap()
+        }
+    }
 }
@@ -0,0 +1,132 @@
+/// Thread related pokes together using a correlation key
+use headjack::Bot;
+use lazy_static::lazy_static;
+use matrix_sdk::ruma::OwnedEventId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::error;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::utils::MessageRelation;
+
+/// Default time a group is remembered after its last poke
+pub const DEFAULT_GROUP_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+lazy_static! {
+    /// The groups, loaded from the state dir on first use
+    static ref GROUPS: Mutex<Option<Groups>> = Mutex::new(None);
+    /// Held while sending in a group, so only one poke can become its root
+    static ref SENDING: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+/// The thread root for a group in a room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GroupThread {
+    event_id: OwnedEventId,
+    /// Unix timestamp of the last poke in the group
+    last_used: u64,
+}
+
+/// Groups saved to a file, keyed by room and group key
+struct Groups {
+    path: PathBuf,
+    threads: HashMap<String, GroupThread>,
+}
+
+impl Groups {
+    fn load(path: PathBuf) -> Self {
+        let threads = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        Groups { path, threads }
+    }
+
+    fn save(&self) {
+        let result = serde_json::to_string(&self.threads)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(std::fs::write(&self.path, contents)?));
+        if let Err(e) = result {
+            error!("Failed to save groups to {}: {:?}", self.path.display(), e);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Send a poke in the thread for its group.
+///
+/// The first poke in a group becomes the thread root, and later pokes are sent in that thread
+/// until the group hasn't been used for `expiry`.
+pub async fn send_in_group<F, Fut>(
+    bot: &Bot,
+    room_id: &str,
+    key: &str,
+    expiry: Duration,
+    send: F,
+) -> anyhow::Result<Option<OwnedEventId>>
+where
+    F: FnOnce(Option<MessageRelation>) -> Fut,
+    Fut: Future<Output = anyhow::Result<Option<OwnedEventId>>>,
+{
+    let group = format!("{} {}", room_id, key);
+    // Lock the group while sending, other groups can send at the same time
+    let lock = SENDING
+        .lock()
+        .unwrap()
+        .entry(group.clone())
+        .or_default()
+        .clone();
+    let sending = lock.lock().await;
+
+    let now = now();
+    let root = {
+        let mut groups = GROUPS.lock().await;
+        let groups =
+            groups.get_or_insert_with(|| Groups::load(bot.state_dir().join("groups.json")));
+        groups
+            .threads
+            .retain(|_, thread| thread.last_used + expiry.as_secs() > now);
+        groups
+            .threads
+            .get(&group)
+            .map(|thread| thread.event_id.clone())
+    };
+    let result = send(root.clone().map(MessageRelation::Thread)).await;
+    let root = match &result {
+        Ok(event_id) => root.or(event_id.clone()),
+        Err(_) => None,
+    };
+    if let Some(root) = root {
+        let mut groups = GROUPS.lock().await;
+        if let Some(groups) = groups.as_mut() {
+            groups.threads.insert(
+                group.clone(),
+                GroupThread {
+                    event_id: root,
+                    last_used: now,
+                },
+            );
+            groups.save();
+        }
+    }
+    drop(sending);
+
+    // Forget the lock if no other poke is waiting for it
+    let mut locks = SENDING.lock().unwrap();
+    if Arc::strong_count(&lock) == 2 {
+        locks.remove(&group);
+    }
+    result
+}
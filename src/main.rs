@@ -1,15 +1,30 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use reqwest::header::HeaderMap;
 
+mod admin;
+mod attachment;
 mod config;
 mod daemon;
+mod group;
+mod outbox;
+mod reminder;
+mod run;
+mod signature;
+mod subscribe;
+mod tail;
+mod token;
 mod utils;
+mod webhooks;
 
+use crate::attachment::Attachment;
 use crate::config::*;
 use crate::daemon::daemon;
+use crate::run::run_command;
+use crate::tail::{follow_file, read_lines, send_batches, StreamArgs};
 use crate::utils::*;
 
 use is_terminal::IsTerminal;
+use matrix_sdk::ruma::{EventId, OwnedEventId};
 use std::{fs::File, io::Read, path::PathBuf};
 use tracing::{error, info};
 
@@ -17,11 +32,11 @@ use tracing::{error, info};
 #[command(author, version, about, long_about = None)]
 struct PokemArgs {
     /// Path to config file
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     config: Option<PathBuf>,
 
     /// Room ID to send the message to
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     room: Option<String>,
 
     /// Run in daemon mode
@@ -29,18 +44,98 @@ struct PokemArgs {
     daemon: bool,
 
     /// Authentication token
-    #[arg(long, visible_alias = "auth")]
+    #[arg(long, visible_alias = "auth", global = true)]
     authentication: Option<String>,
 
     /// Formatting for the message. "markdown" or "plain".
-    #[arg(long)]
+    #[arg(long, global = true)]
     format: Option<String>,
 
+    /// Title of the message
+    #[arg(long, global = true)]
+    title: Option<String>,
+
+    /// Priority of the message, 1-5 or min/low/default/high/urgent.
+    /// Urgent messages go to the "<room>-urgent" room if it exists, otherwise they mention the @room.
+    #[arg(short, long, global = true)]
+    priority: Option<String>,
+
+    /// Comma separated tags, emoji shortcodes are shown as emojis
+    #[arg(short, long, value_delimiter = ',', global = true)]
+    tags: Option<Vec<String>>,
+
+    /// File to send along with the message
+    #[arg(long, visible_alias = "file")]
+    attach: Option<PathBuf>,
+
+    /// Edit an earlier message instead of sending a new one, using the event ID printed when it was sent
+    #[arg(long, value_name = "EVENT_ID", conflicts_with_all = ["thread", "reply"])]
+    edit: Option<OwnedEventId>,
+
+    /// Send the message in the thread started by this event
+    #[arg(long, value_name = "EVENT_ID", global = true, conflicts_with = "reply")]
+    thread: Option<OwnedEventId>,
+
+    /// Send the message as a reply to this event
+    #[arg(long, value_name = "EVENT_ID", global = true)]
+    reply: Option<OwnedEventId>,
+
+    /// Send stdin line by line as it arrives, instead of waiting for EOF
+    #[arg(long)]
+    stream: bool,
+
+    #[command(flatten)]
+    stream_options: StreamArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Message to send
     #[arg()]
     message: Option<Vec<String>>,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Run a command and send a message with the outcome.
+    /// Failures are sent as urgent.
+    Run {
+        /// Only send a message if the command fails
+        #[arg(long)]
+        on_failure_only: bool,
+
+        /// Number of output lines to include in the message
+        #[arg(long, default_value_t = 10)]
+        lines: usize,
+
+        /// The command to run
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Follow a file and send new lines as they are written.
+    /// Reads from stdin if no file is given.
+    Tail {
+        /// File to follow
+        path: Option<PathBuf>,
+
+        #[command(flatten)]
+        options: StreamArgs,
+    },
+}
+
+/// A message to send from the CLI
+#[derive(Default)]
+struct Poke {
+    /// Room name from the config, or the room id
+    room: String,
+    message: String,
+    title: Option<String>,
+    priority: Option<u8>,
+    tags: Option<Vec<String>>,
+    attachment: Option<Attachment>,
+    relation: Option<MessageRelation>,
+}
+
 /// Get the config from the file or load the default config
 fn get_config_or_default(path: &Option<PathBuf>) -> Config {
     let mut file = {
@@ -84,37 +179,62 @@ async fn main() -> anyhow::Result<()> {
         return daemon(config.daemon, config.rooms).await;
     }
 
-    let headers = {
-        let mut headers = HeaderMap::new();
-        if let Some(auth) = args.authentication.clone() {
-            headers.insert("Authentication", auth.parse().unwrap());
-        }
-        if let Some(format) = args.format.clone() {
-            headers.insert("Format", format.parse().unwrap());
+    let headers = get_headers(&args);
+
+    if let Some(Command::Run {
+        on_failure_only,
+        lines,
+        command,
+    }) = &args.command
+    {
+        let outcome = run_command(command, *lines).await;
+        if !outcome.success() || !on_failure_only {
+            let mut tags = args.tags.clone().unwrap_or_default();
+            tags.push(outcome.tag().to_string());
+            let poke = Poke {
+                room: get_default_room(&args, &config)?,
+                message: outcome.summary(),
+                title: Some(args.title.clone().unwrap_or(command.join(" "))),
+                // Failures are always urgent
+                priority: if outcome.success() {
+                    args.priority.as_deref().map(parse_priority)
+                } else {
+                    Some(5)
+                },
+                tags: Some(tags),
+                relation: get_relation(&args),
+                ..Default::default()
+            };
+            if let Err(e) = send_poke(&config, &headers, &poke).await {
+                error!("Failed to send message: {:?}", e);
+            }
         }
-        headers
-    };
+        std::process::exit(outcome.exit_code());
+    }
+
+    if let Some(Command::Tail { path, options }) = &args.command {
+        let lines = match path {
+            Some(path) => follow_file(path.clone()),
+            None => read_lines(tokio::io::stdin()),
+        };
+        let room = get_default_room(&args, &config)?;
+        stream_lines(&config, &headers, &args, &room, lines, options).await;
+        return Ok(());
+    }
 
     let mut messages = args.message.clone().unwrap_or_default();
     let room = {
-        let rooms = config.rooms.unwrap_or_default();
+        let rooms = config.rooms.clone().unwrap_or_default();
         match args.room.clone() {
-            Some(room) => {
-                // If the room is a room name in the config, we'll transform it to the room id
-                if let Some(room_id) = rooms.get(&room) {
-                    room_id.clone()
-                } else {
-                    room
-                }
-            }
+            Some(room) => room,
             None => {
                 // Create a regex to see if the first argument looks like a room name
                 let re = regex::Regex::new(r"^.*:.*\..*").unwrap();
                 if messages.is_empty() {
                     // Check if there is a default room configured
                     // That room will be pinged with no message
-                    if let Some(room_id) = rooms.get("default") {
-                        room_id.clone()
+                    if rooms.contains_key("default") {
+                        "default".to_string()
                     } else {
                         return Err(anyhow::anyhow!("No room specified"));
                     }
@@ -123,29 +243,180 @@ async fn main() -> anyhow::Result<()> {
                     // TODO: This has surprising behavior if this isn't an intended room, we'd want to fall back to the configured default room
                     // I suppose we could fallback in this CLI? e.g. if the command fails to identify a room, then try the default room
                     messages.remove(0)
-                } else if let Some(room_id) = rooms.get(&messages[0]) {
+                } else if rooms.contains_key(&messages[0]) {
                     // Check for a room name in the config
-                    messages.remove(0);
-                    room_id.clone()
-                } else if let Some(room_id) = rooms.get("default") {
+                    messages.remove(0)
+                } else if rooms.contains_key("default") {
                     // Check if a default room exists
-                    room_id.clone()
+                    "default".to_string()
                 } else {
                     return Err(anyhow::anyhow!("No room specified"));
                 }
             }
         }
     };
+
     error!("Room: {:?}, Message: {:?}", room, messages);
 
+    if args.stream {
+        if !messages.is_empty() {
+            return Err(anyhow::anyhow!("A message can't be used with --stream"));
+        }
+        let lines = read_lines(tokio::io::stdin());
+        stream_lines(&config, &headers, &args, &room, lines, &args.stream_options).await;
+        return Ok(());
+    }
+
+    let mut attachment = match &args.attach {
+        Some(path) => {
+            let data = std::fs::read(path)?;
+            let filename = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or("attachment".to_string());
+            Some(Attachment::new(&filename, data, None))
+        }
+        None => None,
+    };
+
     // Append any stdin content to the message
-    let mut input = String::new();
+    let mut input = Vec::new();
     if !std::io::stdin().is_terminal() {
-        std::io::stdin().read_to_string(&mut input).unwrap();
-        if !input.is_empty() {
-            messages.push(input.trim().to_string());
+        std::io::stdin().read_to_end(&mut input)?;
+        match String::from_utf8(input) {
+            Ok(input) => {
+                if !input.is_empty() {
+                    messages.push(input.trim().to_string());
+                }
+            }
+            Err(e) => {
+                // Binary data is sent as a file
+                if attachment.is_some() {
+                    return Err(anyhow::anyhow!(
+                        "Can't send binary stdin with an attachment"
+                    ));
+                }
+                attachment = Some(Attachment::new("stdin", e.into_bytes(), None));
+            }
+        }
+    }
+
+    let poke = Poke {
+        room,
+        message: messages.join(" "),
+        title: args.title.clone(),
+        priority: args.priority.as_deref().map(parse_priority),
+        tags: args.tags.clone(),
+        attachment,
+        relation: get_relation(&args),
+    };
+    // Print the event ID so that the message can be edited later
+    if let Some(event_id) = send_poke(&config, &headers, &poke).await? {
+        println!("{}", event_id);
+    }
+    Ok(())
+}
+
+/// Send batches of lines to the room until there are no more lines
+async fn stream_lines(
+    config: &Config,
+    headers: &HeaderMap,
+    args: &PokemArgs,
+    room: &str,
+    lines: tokio::sync::mpsc::Receiver<String>,
+    options: &StreamArgs,
+) {
+    send_batches(lines, options, |batch| {
+        let poke = Poke {
+            room: room.to_string(),
+            // Logs are often not valid markdown
+            message: format!("```\n{}\n```", batch.join("\n")),
+            title: args.title.clone(),
+            priority: args.priority.as_deref().map(parse_priority),
+            tags: args.tags.clone(),
+            relation: get_relation(args),
+            ..Default::default()
+        };
+        async move {
+            if let Err(e) = send_poke(config, headers, &poke).await {
+                error!("Failed to send message: {:?}", e);
+            }
         }
+    })
+    .await;
+}
+
+/// Build the headers from the CLI args
+fn get_headers(args: &PokemArgs) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(auth) = args.authentication.clone() {
+        headers.insert("Authentication", auth.parse().unwrap());
     }
+    if let Some(format) = args.format.clone() {
+        headers.insert("Format", format.parse().unwrap());
+    }
+    headers
+}
+
+/// Get the earlier message to edit, thread under or reply to from the CLI args
+fn get_relation(args: &PokemArgs) -> Option<MessageRelation> {
+    if let Some(event_id) = &args.edit {
+        Some(MessageRelation::Replaces(event_id.clone()))
+    } else if let Some(event_id) = &args.thread {
+        Some(MessageRelation::Thread(event_id.clone()))
+    } else {
+        args.reply.clone().map(MessageRelation::Reply)
+    }
+}
+
+/// Get the room from the args, or the default room from the config
+fn get_default_room(args: &PokemArgs, config: &Config) -> anyhow::Result<String> {
+    match &args.room {
+        Some(room) => Ok(room.clone()),
+        None if config
+            .rooms
+            .as_ref()
+            .is_some_and(|r| r.contains_key("default")) =>
+        {
+            Ok("default".to_string())
+        }
+        None => Err(anyhow::anyhow!("No room specified")),
+    }
+}
+
+/// Send a poke through the configured server, or by logging in to Matrix.
+///
+/// Returns the event ID of the message if it's known.
+async fn send_poke(
+    config: &Config,
+    headers: &HeaderMap,
+    poke: &Poke,
+) -> anyhow::Result<Option<OwnedEventId>> {
+    // If the room is a room name in the config, we'll transform it to the room id
+    let (room, mention_room) =
+        resolve_room(config.rooms.as_ref(), &poke.room, is_urgent(poke.priority));
+
+    // The server builds the message from these, so the output matches the direct login
+    let query = {
+        let mut query = Vec::new();
+        if let Some(title) = poke.title.clone() {
+            query.push(("title", title));
+        }
+        if let Some(tags) = poke.tags.clone() {
+            query.push(("tags", tags.join(",")));
+        }
+        // An urgent room has already been picked, so only send the priority if the server
+        // should mention the @room
+        if let Some(priority) = poke.priority {
+            if mention_room || !is_urgent(Some(priority)) {
+                query.push(("priority", priority.to_string()));
+            }
+        }
+        if let Some(relation) = &poke.relation {
+            query.push(relation.query_param());
+        }
+        query
+    };
 
     if config.server.is_none() && config.matrix.is_none() {
         // The user has set neither server nor matrix config
@@ -155,10 +426,19 @@ async fn main() -> anyhow::Result<()> {
             url: "https://pokem.dev".to_string(),
             port: None,
         };
-        match poke_server(&server, &room, &headers, &messages.join(" ")).await {
-            Ok(_) => {
+        match poke_server(
+            &server,
+            &room,
+            headers,
+            &query,
+            &poke.message,
+            poke.attachment.as_ref(),
+        )
+        .await
+        {
+            Ok(event_id) => {
                 info!("Successfully sent message");
-                return Ok(());
+                return Ok(event_id);
             }
             Err(e) => {
                 error!("Failed to send message: {:?}", e);
@@ -166,12 +446,21 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    if let Some(server) = config.server {
+    if let Some(server) = &config.server {
         info!("Sending request to server");
-        match poke_server(&server, &room, &headers, &messages.join(" ")).await {
-            Ok(_) => {
+        match poke_server(
+            server,
+            &room,
+            headers,
+            &query,
+            &poke.message,
+            poke.attachment.as_ref(),
+        )
+        .await
+        {
+            Ok(event_id) => {
                 info!("Successfully sent message");
-                return Ok(());
+                return Ok(event_id);
             }
             Err(e) => {
                 error!("Failed to send message: {:?}", e);
@@ -179,33 +468,58 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    if let Some(matrix) = config.matrix {
-        info!("Running as a Matrix client");
-        // Login to matrix
-        let bot = connect(matrix).await?;
-        GLOBAL_BOT.lock().unwrap().replace(bot.clone());
+    if let Some(matrix) = &config.matrix {
+        // Reuse the login if we've already connected
+        let existing_bot = GLOBAL_BOT.lock().unwrap().clone();
+        let bot = match existing_bot {
+            Some(bot) => bot,
+            None => {
+                info!("Running as a Matrix client");
+                // Login to matrix
+                let bot = connect(matrix.clone()).await?;
+                GLOBAL_BOT.lock().unwrap().replace(bot.clone());
+                bot
+            }
+        };
         // Ping the room
-        return ping_room(&bot, &room, &headers, &messages.join(" "), false).await;
+        let message = compose_message(&poke.message, poke.title.as_deref(), poke.tags.as_deref());
+        return ping_room(
+            &bot,
+            &room,
+            headers,
+            &message,
+            mention_room,
+            poke.attachment.as_ref(),
+            poke.relation.as_ref(),
+        )
+        .await;
     }
 
-    return Err(anyhow::anyhow!("Unable to send message"));
+    Err(anyhow::anyhow!("Unable to send message"))
 }
 
 /// Send a message to the server.
+///
+/// With an attachment, the file is uploaded as the body and the message is sent as a query
+/// parameter.
+///
+/// Returns the event ID from the response, older servers only respond with "OK".
 async fn poke_server(
     server: &ServerConfig,
     room: &str,
     headers: &reqwest::header::HeaderMap,
+    query: &[(&str, String)],
     message: &str,
-) -> anyhow::Result<()> {
+    attachment: Option<&Attachment>,
+) -> anyhow::Result<Option<OwnedEventId>> {
     // URI encode the room
     let room = urlencoding::encode(room).to_string();
 
     let url = {
-        if server.port.is_none() {
-            format!("{}/{}", server.url, room)
+        if let Some(port) = server.port {
+            format!("{}:{}/{}", server.url, port, room)
         } else {
-            format!("{}:{}/{}", server.url, server.port.unwrap(), room)
+            format!("{}/{}", server.url, room)
         }
     };
     // if url doesn't start with "http://" or "https://", add "http://" to the beginning
@@ -217,17 +531,26 @@ async fn poke_server(
     };
 
     let client = reqwest::Client::new();
-    let res = client
-        .post(&url)
-        .body(message.to_owned())
-        .headers(headers.clone())
-        .send()
-        .await?;
+    let request = if let Some(attachment) = attachment {
+        let mut request = client
+            .put(&url)
+            .body(attachment.data.clone())
+            // Filenames may not be valid header values
+            .query(&[("filename", attachment.filename.as_str())])
+            .header("Content-Type", attachment.content_type.essence_str());
+        if !message.is_empty() {
+            request = request.query(&[("message", message)]);
+        }
+        request
+    } else {
+        client.post(&url).body(message.to_owned())
+    };
+    let res = request.query(query).headers(headers.clone()).send().await?;
 
     if res.status().is_success() {
         let body = res.text().await?;
         error!("Response: {:?}", body);
-        Ok(())
+        Ok(EventId::parse(body.trim()).ok())
     } else {
         error!("Failed to send message: {:?}", res.status());
         Err(anyhow::anyhow!("Failed to send message"))
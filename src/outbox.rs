@@ -0,0 +1,376 @@
+/// Persistent outbox to retry pokes that failed to send
+use crate::attachment::Attachment;
+use crate::group::send_in_group;
+use crate::token::count_poke;
+use crate::utils::{find_joined_room, send_to_room, MessageRelation, RetryableError};
+
+use headjack::Bot;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use lazy_static::lazy_static;
+use matrix_sdk::ruma::OwnedEventId;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{error, info};
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default time to keep retrying a poke
+pub const DEFAULT_QUEUE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Furthest ahead a poke can be scheduled, the same as ntfy's default
+pub const MAX_DELAY: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+/// Wait before the first retry, doubling after every attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Longest wait between retries
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// How often to check for pokes that are due, if nothing wakes the outbox
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    /// The outbox database, opened when the daemon starts
+    static ref OUTBOX: Mutex<Option<Connection>> = Mutex::new(None);
+    /// Wakes the outbox when a poke is queued
+    static ref WAKE: Notify = Notify::new();
+}
+
+/// A poke that is ready to send, and can be saved to the outbox
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedPoke {
+    pub room_id: String,
+    pub message: String,
+    /// The request headers that select the format.
+    /// The authentication token was already checked, so it isn't saved
+    pub headers: Vec<(String, String)>,
+    pub mention_room: bool,
+    pub relation: Option<MessageRelation>,
+    /// Correlation key, pokes with the same key are sent in the same thread
+    pub group: Option<String>,
+    /// Stored separately, so the file isn't encoded as JSON
+    #[serde(skip)]
+    pub attachment: Option<Attachment>,
+    /// Counts the poke towards the rate limit of the token that sent it, once it is sent
+    #[serde(default)]
+    pub rate_key: Option<String>,
+}
+
+impl QueuedPoke {
+    /// Keep the format headers that can be stored as strings
+    pub fn headers_from(headers: &HeaderMap) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .filter(|(name, _)| *name == "format")
+            .filter_map(|(name, value)| {
+                value
+                    .to_str()
+                    .ok()
+                    .map(|value| (name.to_string(), value.to_string()))
+            })
+            .collect()
+    }
+
+    fn header_map(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        headers
+    }
+
+    /// Send the poke to the room, in the thread for its group if it has one.
+    ///
+    /// The authentication token must be checked before the poke is queued.
+    pub async fn send(
+        &self,
+        bot: &Bot,
+        group_expiry: Duration,
+    ) -> anyhow::Result<Option<OwnedEventId>> {
+        let headers = self.header_map();
+        let send = |relation: Option<MessageRelation>| {
+            let headers = &headers;
+            async move {
+                let room = find_joined_room(bot, &self.room_id).await?;
+                send_to_room(
+                    &room,
+                    headers,
+                    &self.message,
+                    self.mention_room,
+                    self.attachment.as_ref(),
+                    relation.as_ref(),
+                )
+                .await
+            }
+        };
+        let result = match &self.group {
+            Some(key) => send_in_group(bot, &self.room_id, key, group_expiry, send).await,
+            None => send(self.relation.clone()).await,
+        };
+        if let (Ok(_), Some(rate_key)) = (&result, &self.rate_key) {
+            count_poke(rate_key);
+        }
+        result
+    }
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Open the outbox database in the state directory
+pub fn open(state_dir: &Path) -> anyhow::Result<()> {
+    let connection = Connection::open(state_dir.join("outbox.sqlite3"))?;
+    connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS pokes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            poke TEXT NOT NULL,
+            attachment_name TEXT,
+            attachment_type TEXT,
+            attachment BLOB,
+            queued_at INTEGER NOT NULL,
+            send_at INTEGER NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0
+        );",
+    )?;
+    OUTBOX.lock().unwrap().replace(connection);
+    Ok(())
+}
+
+/// Save a poke to the outbox, to be sent at the given time.
+///
+/// `attempts` is the number of times sending has already failed.
+/// Returns the queue ID of the poke.
+pub fn enqueue(poke: &QueuedPoke, send_at: SystemTime, attempts: u32) -> anyhow::Result<i64> {
+    let outbox = OUTBOX.lock().unwrap();
+    let Some(connection) = outbox.as_ref() else {
+        return Err(anyhow::anyhow!("The outbox is not open"));
+    };
+    // The max age counts from when the poke was first due to be sent
+    let queued_at = if attempts > 0 {
+        SystemTime::now()
+    } else {
+        send_at
+    };
+    connection.execute(
+        "INSERT INTO pokes
+            (poke, attachment_name, attachment_type, attachment, queued_at, send_at, attempts)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            serde_json::to_string(poke)?,
+            poke.attachment.as_ref().map(|a| a.filename.clone()),
+            poke.attachment.as_ref().map(|a| a.content_type.to_string()),
+            poke.attachment.as_ref().map(|a| a.data.clone()),
+            unix_time(queued_at),
+            unix_time(send_at),
+            attempts,
+        ],
+    )?;
+    let id = connection.last_insert_rowid();
+    WAKE.notify_one();
+    Ok(id)
+}
+
+/// A poke waiting in the outbox, as listed over HTTP
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingPoke {
+    pub id: i64,
+    #[serde(skip)]
+    pub room_id: String,
+    /// Unix timestamp of the next attempt
+    pub time: i64,
+    pub message: String,
+    /// Number of failed attempts
+    pub attempts: u32,
+}
+
+/// Get all the pokes waiting in the outbox, in the order they will be sent
+pub fn pending() -> anyhow::Result<Vec<PendingPoke>> {
+    with_outbox(|connection| {
+        let mut statement = connection
+            .prepare("SELECT id, poke, send_at, attempts FROM pokes ORDER BY send_at, id")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, u32>(3)?,
+            ))
+        })?;
+        let mut pending = Vec::new();
+        for row in rows {
+            let (id, poke, time, attempts) = row?;
+            if let Ok(poke) = serde_json::from_str::<QueuedPoke>(&poke) {
+                pending.push(PendingPoke {
+                    id,
+                    room_id: poke.room_id,
+                    time,
+                    message: poke.message,
+                    attempts,
+                });
+            }
+        }
+        Ok(pending)
+    })
+}
+
+/// Remove a poke from the outbox so it won't be sent
+pub fn cancel(id: i64) -> anyhow::Result<()> {
+    with_outbox(|connection| remove(connection, id))
+}
+
+/// A poke in the outbox
+struct Entry {
+    id: i64,
+    poke: QueuedPoke,
+    queued_at: i64,
+    attempts: u32,
+}
+
+/// Get the next poke that is due to be sent
+fn next_due(connection: &Connection, now: i64) -> anyhow::Result<Option<Entry>> {
+    let row = connection
+        .query_row(
+            "SELECT id, poke, attachment_name, attachment_type, attachment, queued_at, attempts
+                FROM pokes WHERE send_at <= ?1 ORDER BY send_at, id LIMIT 1",
+            params![now],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<Vec<u8>>>(4)?,
+                    row.get::<_, i64>(5)?,
+                    row.get::<_, u32>(6)?,
+                ))
+            },
+        )
+        .optional()?;
+    let Some((id, poke, name, content_type, data, queued_at, attempts)) = row else {
+        return Ok(None);
+    };
+    let mut poke: QueuedPoke = match serde_json::from_str(&poke) {
+        Ok(poke) => poke,
+        Err(e) => {
+            // It can never be sent, so don't keep it around
+            error!("Removing unreadable queued poke {}: {:?}", id, e);
+            remove(connection, id)?;
+            return next_due(connection, now);
+        }
+    };
+    if let (Some(name), Some(data)) = (name, data) {
+        poke.attachment = Some(Attachment::new(&name, data, content_type.as_deref()));
+    }
+    Ok(Some(Entry {
+        id,
+        poke,
+        queued_at,
+        attempts,
+    }))
+}
+
+fn remove(connection: &Connection, id: i64) -> anyhow::Result<()> {
+    connection.execute("DELETE FROM pokes WHERE id = ?1", params![id])?;
+    Ok(())
+}
+
+/// Run a database operation on the outbox
+pub fn with_outbox<T>(f: impl FnOnce(&Connection) -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let outbox = OUTBOX.lock().unwrap();
+    let Some(connection) = outbox.as_ref() else {
+        return Err(anyhow::anyhow!("The outbox is not open"));
+    };
+    f(connection)
+}
+
+/// How long to wait before the next attempt
+pub fn backoff(attempts: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+/// Send the queued pokes as they become due.
+///
+/// Pokes that fail with a RetryableError are retried with exponential backoff, or after the
+/// time the homeserver asks for, until they are older than `max_age`.
+pub async fn run(bot: Bot, group_expiry: Duration, max_age: Duration) {
+    loop {
+        loop {
+            let now = unix_time(SystemTime::now());
+            let entry = match with_outbox(|connection| next_due(connection, now)) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to read the outbox: {:?}", e);
+                    break;
+                }
+            };
+            if now - entry.queued_at > max_age.as_secs() as i64 {
+                error!(
+                    "Dropping queued poke {} for {}, it could not be sent after {} attempts",
+                    entry.id, entry.poke.room_id, entry.attempts
+                );
+                let _ = with_outbox(|connection| remove(connection, entry.id));
+                continue;
+            }
+
+            let result = entry.poke.send(&bot, group_expiry).await;
+            let update = with_outbox(|connection| match &result {
+                Ok(_) => {
+                    info!("Sent queued poke {}", entry.id);
+                    remove(connection, entry.id)
+                }
+                Err(e) => match e.downcast_ref::<RetryableError>() {
+                    Some(retryable) => {
+                        let attempts = entry.attempts + 1;
+                        let wait = retryable.retry_after.unwrap_or(backoff(attempts));
+                        error!(
+                            "Failed to send queued poke {}, retrying in {}s: {}",
+                            entry.id,
+                            wait.as_secs(),
+                            e
+                        );
+                        connection.execute(
+                            "UPDATE pokes SET attempts = ?1, send_at = ?2 WHERE id = ?3",
+                            params![attempts, unix_time(SystemTime::now() + wait), entry.id],
+                        )?;
+                        // Don't post the attachment again with the message
+                        if retryable.attachment_sent {
+                            connection.execute(
+                                "UPDATE pokes SET attachment_name = NULL, attachment_type = NULL,
+                                    attachment = NULL WHERE id = ?1",
+                                params![entry.id],
+                            )?;
+                        }
+                        Ok(())
+                    }
+                    None => {
+                        error!("Dropping queued poke {}: {:?}", entry.id, e);
+                        remove(connection, entry.id)
+                    }
+                },
+            });
+            if let Err(e) = update {
+                error!("Failed to update the outbox: {:?}", e);
+                break;
+            }
+        }
+        tokio::select! {
+            _ = WAKE.notified() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
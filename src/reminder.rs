@@ -0,0 +1,390 @@
+/// Reminders set from Matrix with `!pokem remind`
+use chrono::{DateTime, Datelike, Days, Local, NaiveTime, TimeDelta, TimeZone, Weekday};
+use headjack::Bot;
+use lazy_static::lazy_static;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::RoomId;
+use rusqlite::{params, Connection, Params};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::error;
+
+use std::time::Duration;
+
+use crate::outbox::with_outbox;
+use crate::utils::{can_message_room, parse_delay, parse_duration, parse_time_of_day};
+
+/// Longest time to sleep before checking the reminders again
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// Wakes the reminder task when a reminder is added
+    static ref WAKE: Notify = Notify::new();
+}
+
+/// When a reminder is sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Schedule {
+    /// Only once
+    Once,
+    /// Repeat after every interval, in seconds
+    Interval(u64),
+    /// At a time of day, on the given days of the week
+    Weekly { days: Vec<Weekday>, time: NaiveTime },
+}
+
+impl Schedule {
+    /// Get the next time after `after`
+    fn next(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Schedule::Once => None,
+            Schedule::Interval(seconds) => add(after, Duration::from_secs(*seconds)),
+            Schedule::Weekly { days, time } => (0..=7).find_map(|offset| {
+                let date = after.date_naive() + Days::new(offset);
+                let at = date.and_time(*time).and_local_timezone(Local).earliest()?;
+                (days.contains(&date.weekday()) && at > after).then_some(at)
+            }),
+        }
+    }
+
+    /// Get the next time after a reminder that was due at `due`.
+    ///
+    /// Intervals count from when the reminder was due so they don't drift, and any that were
+    /// missed while the daemon was down are skipped.
+    fn following(&self, due: DateTime<Local>, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Schedule::Interval(seconds) => {
+                let seconds = (*seconds).max(1);
+                let missed = (now - due).num_seconds().max(0) as u64 / seconds;
+                add(due, Duration::from_secs(seconds.checked_mul(missed + 1)?))
+            }
+            _ => self.next(now),
+        }
+    }
+
+    /// Describe the schedule for listing reminders
+    fn describe(&self) -> String {
+        match self {
+            Schedule::Once => "once".to_string(),
+            Schedule::Interval(seconds) => format!("every {}", format_interval(*seconds)),
+            Schedule::Weekly { days, time } => {
+                let days = if days.len() == 7 {
+                    "day".to_string()
+                } else {
+                    days.iter()
+                        .map(|day| day.to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                };
+                format!("every {} {}", days, time.format("%H:%M"))
+            }
+        }
+    }
+}
+
+fn format_interval(seconds: u64) -> String {
+    for (unit, size) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60)] {
+        if seconds.is_multiple_of(size) {
+            return format!("{}{}", seconds / size, unit);
+        }
+    }
+    format!("{}s", seconds)
+}
+
+/// A reminder for a room
+#[derive(Debug, Clone)]
+struct Reminder {
+    id: i64,
+    room_id: String,
+    message: String,
+    schedule: Schedule,
+    /// Unix timestamp of the next reminder
+    next: i64,
+}
+
+/// Create the reminders table in the outbox database
+pub fn open() -> anyhow::Result<()> {
+    with_outbox(|connection| {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS reminders (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                room_id TEXT NOT NULL,
+                message TEXT NOT NULL,
+                schedule TEXT NOT NULL,
+                next INTEGER NOT NULL
+            );",
+        )?;
+        Ok(())
+    })
+}
+
+/// Get the reminders matching the condition, in the order they are due
+fn load(
+    connection: &Connection,
+    condition: &str,
+    params: impl Params,
+) -> anyhow::Result<Vec<Reminder>> {
+    let mut statement = connection.prepare(&format!(
+        "SELECT id, room_id, message, schedule, next FROM reminders {} ORDER BY next, id",
+        condition
+    ))?;
+    let rows = statement.query_map(params, |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, i64>(4)?,
+        ))
+    })?;
+    let mut reminders = Vec::new();
+    for row in rows {
+        let (id, room_id, message, schedule, next) = row?;
+        match serde_json::from_str(&schedule) {
+            Ok(schedule) => reminders.push(Reminder {
+                id,
+                room_id,
+                message,
+                schedule,
+                next,
+            }),
+            Err(e) => error!("Invalid schedule for reminder {}: {:?}", id, e),
+        }
+    }
+    Ok(reminders)
+}
+
+/// Parse days of the week like "mon", "mon,wed,fri", "weekday", "weekend" or "day"
+fn parse_days(days: &str) -> Option<Vec<Weekday>> {
+    let mut parsed = Vec::new();
+    for day in days.to_lowercase().split(',') {
+        match day.trim() {
+            "day" | "days" | "daily" => return Some(Vec::from(ALL_DAYS)),
+            "weekday" | "weekdays" => parsed.extend(&ALL_DAYS[..5]),
+            "weekend" | "weekends" => parsed.extend(&ALL_DAYS[5..]),
+            day => parsed.push(day.parse::<Weekday>().ok()?),
+        }
+    }
+    Some(parsed)
+}
+
+const ALL_DAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// Add a duration to a time, or None if it's too far in the future
+fn add(time: DateTime<Local>, duration: Duration) -> Option<DateTime<Local>> {
+    time.checked_add_signed(TimeDelta::from_std(duration).ok()?)
+}
+
+/// Parse the reminder, e.g. "in 3h check backups" or "every mon 09:00 Standup notes due".
+///
+/// Returns the schedule, the first time and the message.
+fn parse_reminder(args: &[&str]) -> Option<(Schedule, DateTime<Local>, String)> {
+    let now = Local::now();
+    let (schedule, first, rest) = match args {
+        ["in", duration, rest @ ..] => (Schedule::Once, add(now, parse_duration(duration)?)?, rest),
+        ["at", day, time, rest @ ..] if day.trim_end_matches(',') == "tomorrow" => (
+            Schedule::Once,
+            add(now, parse_delay(&format!("tomorrow {}", time)).ok()?)?,
+            rest,
+        ),
+        ["at", time, rest @ ..] => (Schedule::Once, add(now, parse_delay(time).ok()?)?, rest),
+        ["every", interval, rest @ ..] if parse_duration(interval).is_some() => {
+            let interval = parse_duration(interval)?;
+            if interval.as_secs() < 60 {
+                return None;
+            }
+            (
+                Schedule::Interval(interval.as_secs()),
+                add(now, interval)?,
+                rest,
+            )
+        }
+        ["every", days, time, rest @ ..] => {
+            let schedule = Schedule::Weekly {
+                days: parse_days(days)?,
+                time: parse_time_of_day(&time.to_lowercase())?,
+            };
+            let first = schedule.next(now)?;
+            (schedule, first, rest)
+        }
+        _ => return None,
+    };
+    let message = rest.join(" ");
+    if message.is_empty() {
+        return None;
+    }
+    // Round to the nearest second, the time was measured from a slightly different now
+    let first = Local
+        .timestamp_opt((first + Duration::from_millis(500)).timestamp(), 0)
+        .single()?;
+    Some((schedule, first, message))
+}
+
+/// Handle `!pokem remind ...`, and respond with the result
+pub async fn remind_command(room_id: &RoomId, args: &[&str]) -> String {
+    if let ["cancel", id] = args {
+        let Ok(id) = id.parse::<i64>() else {
+            return format!("There is no reminder {} in this room", id);
+        };
+        let removed = with_outbox(|connection| {
+            Ok(connection.execute(
+                "DELETE FROM reminders WHERE id = ?1 AND room_id = ?2",
+                params![id, room_id.as_str()],
+            )?)
+        });
+        return match removed {
+            Ok(0) => format!("There is no reminder {} in this room", id),
+            Ok(_) => format!("Cancelled reminder {}", id),
+            Err(e) => {
+                error!("Failed to cancel reminder {}: {:?}", id, e);
+                "ERROR: Failed to cancel the reminder".to_string()
+            }
+        };
+    }
+
+    let Some((schedule, first, message)) = parse_reminder(args) else {
+        return "Usage:\n\
+            - `remind in <duration> <message>`, e.g. `remind in 3h check backups`\n\
+            - `remind at <time> <message>`, e.g. `remind at tomorrow 10am renew the certificate`\n\
+            - `remind every <days> <time> <message>`, e.g. `remind every mon 09:00 Standup notes due`\n\
+            - `remind every <duration> <message>`, e.g. `remind every 12h rotate the logs`\n\
+            - `remind cancel <id>`\n\n\
+            Days can be `day`, `weekday`, `weekend` or a list like `mon,wed,fri`."
+            .to_string();
+    };
+
+    let id = with_outbox(|connection| {
+        connection.execute(
+            "INSERT INTO reminders (room_id, message, schedule, next) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                room_id.as_str(),
+                message,
+                serde_json::to_string(&schedule)?,
+                first.timestamp(),
+            ],
+        )?;
+        Ok(connection.last_insert_rowid())
+    });
+    match id {
+        Ok(id) => {
+            WAKE.notify_one();
+            format!(
+                "Reminder {} set, {}, next at {}",
+                id,
+                schedule.describe(),
+                first.format("%a %Y-%m-%d %H:%M")
+            )
+        }
+        Err(e) => {
+            error!("Failed to save the reminder: {:?}", e);
+            "ERROR: Failed to save the reminder".to_string()
+        }
+    }
+}
+
+/// Handle `!pokem reminders`, listing the reminders in the room
+pub async fn list_reminders(room_id: &RoomId) -> String {
+    let reminders = match with_outbox(|connection| {
+        load(connection, "WHERE room_id = ?1", params![room_id.as_str()])
+    }) {
+        Ok(reminders) => reminders,
+        Err(e) => {
+            error!("Failed to load the reminders: {:?}", e);
+            return "ERROR: Failed to load the reminders".to_string();
+        }
+    };
+
+    let lines: Vec<String> = reminders
+        .iter()
+        .map(|reminder| {
+            let next = Local
+                .timestamp_opt(reminder.next, 0)
+                .single()
+                .map(|next| next.format("%a %Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            format!(
+                "- {}: {}, next at {} - {}",
+                reminder.id,
+                reminder.schedule.describe(),
+                next,
+                reminder.message
+            )
+        })
+        .collect();
+    if lines.is_empty() {
+        "There are no reminders in this room".to_string()
+    } else {
+        format!("Reminders:\n{}", lines.join("\n"))
+    }
+}
+
+/// Send the reminders when they are due
+pub async fn run(bot: Bot) {
+    loop {
+        let now = Local::now();
+        let due = with_outbox(|connection| {
+            load(connection, "WHERE next <= ?1", params![now.timestamp()])
+        })
+        .unwrap_or_else(|e| {
+            error!("Failed to load the reminders: {:?}", e);
+            Vec::new()
+        });
+        for reminder in due {
+            let room = RoomId::parse(&reminder.room_id)
+                .ok()
+                .and_then(|room_id| bot.client().get_room(&room_id));
+            match room {
+                Some(room) if can_message_room(&room).await => {
+                    let content = RoomMessageEventContent::text_markdown(&reminder.message);
+                    if let Err(e) = room.send(content).await {
+                        error!("Failed to send reminder {}: {:?}", reminder.id, e);
+                    }
+                }
+                _ => error!("Can't send reminder {} to its room", reminder.id),
+            }
+            // Reminders missed while the daemon was down are only sent once,
+            // and reminders that don't repeat are removed
+            let due = Local
+                .timestamp_opt(reminder.next, 0)
+                .single()
+                .unwrap_or(now);
+            let updated = with_outbox(|connection| {
+                match reminder.schedule.following(due, now) {
+                    Some(next) => connection.execute(
+                        "UPDATE reminders SET next = ?1 WHERE id = ?2",
+                        params![next.timestamp(), reminder.id],
+                    )?,
+                    None => connection
+                        .execute("DELETE FROM reminders WHERE id = ?1", params![reminder.id])?,
+                };
+                Ok(())
+            });
+            if let Err(e) = updated {
+                error!("Failed to update reminder {}: {:?}", reminder.id, e);
+            }
+        }
+
+        let next = with_outbox(|connection| {
+            Ok(
+                connection.query_row("SELECT MIN(next) FROM reminders", [], |row| {
+                    row.get::<_, Option<i64>>(0)
+                })?,
+            )
+        });
+        let sleep = next.ok().flatten().map_or(MAX_SLEEP, |next| {
+            Duration::from_secs((next - Local::now().timestamp()).max(1) as u64).min(MAX_SLEEP)
+        });
+        tokio::select! {
+            _ = WAKE.notified() => {}
+            _ = tokio::time::sleep(sleep) => {}
+        }
+    }
+}
@@ -0,0 +1,133 @@
+/// Run a command and report how it went
+use std::collections::VecDeque;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+
+/// The result of running a command
+pub struct CommandOutcome {
+    /// Exit code, None if it was killed by a signal
+    pub code: Option<i32>,
+    pub duration: Duration,
+    /// The last lines of stdout and stderr
+    pub output: Vec<String>,
+}
+
+impl CommandOutcome {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// Exit code to return from pokem, matching the command
+    pub fn exit_code(&self) -> i32 {
+        self.code.unwrap_or(1)
+    }
+
+    /// Emoji shortcode for the outcome
+    pub fn tag(&self) -> &'static str {
+        if self.success() {
+            "white_check_mark"
+        } else {
+            "x"
+        }
+    }
+
+    /// Summarize the outcome and the output in markdown
+    pub fn summary(&self) -> String {
+        let duration = format_duration(self.duration);
+        let mut summary = match self.code {
+            Some(0) => format!("Succeeded after {duration}"),
+            Some(code) => format!("Failed with exit code {code} after {duration}"),
+            None => format!("Killed by a signal after {duration}"),
+        };
+        if !self.output.is_empty() {
+            summary.push_str(&format!("\n\n```\n{}\n```", self.output.join("\n")));
+        }
+        summary
+    }
+}
+
+/// Run the command, passing the output through while keeping the last `lines` lines.
+pub async fn run_command(command: &[String], lines: usize) -> CommandOutcome {
+    let start = Instant::now();
+    let child = Command::new(&command[0])
+        .args(&command[1..])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            // Use the shell's exit code for a command that can't be run
+            return CommandOutcome {
+                code: Some(127),
+                duration: start.elapsed(),
+                output: vec![format!("Failed to run {}: {}", command[0], e)],
+            };
+        }
+    };
+
+    let output = Arc::new(Mutex::new(VecDeque::new()));
+    let stdout = tokio::spawn(tee(
+        child.stdout.take().unwrap(),
+        tokio::io::stdout(),
+        output.clone(),
+        lines,
+    ));
+    let stderr = tokio::spawn(tee(
+        child.stderr.take().unwrap(),
+        tokio::io::stderr(),
+        output.clone(),
+        lines,
+    ));
+    let status = child.wait().await;
+    let _ = tokio::join!(stdout, stderr);
+
+    let output = output.lock().unwrap().iter().cloned().collect();
+    CommandOutcome {
+        code: status.ok().and_then(|status| status.code()),
+        duration: start.elapsed(),
+        output,
+    }
+}
+
+/// Copy each line to the writer, and keep the last `lines` lines
+async fn tee(
+    reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    output: Arc<Mutex<VecDeque<String>>>,
+    lines: usize,
+) {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    while let Ok(read) = reader.read_until(b'\n', &mut line).await {
+        if read == 0 {
+            break;
+        }
+        let _ = writer.write_all(&line).await;
+        let _ = writer.flush().await;
+        let mut output = output.lock().unwrap();
+        output.push_back(String::from_utf8_lossy(&line).trim_end().to_string());
+        while output.len() > lines {
+            output.pop_front();
+        }
+        line.clear();
+    }
+}
+
+/// Format a duration like "1h 2m 3s"
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds < 60 {
+        return format!("{:.1}s", duration.as_secs_f64());
+    }
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}h {minutes}m {seconds}s")
+    } else {
+        format!("{minutes}m {seconds}s")
+    }
+}
@@ -0,0 +1,189 @@
+/// Requests signed with the room's secret, an alternative to sending the authentication token
+use hmac::{Hmac, Mac};
+use hyper::header::CONTENT_TYPE;
+use hyper::{HeaderMap, Method, Uri};
+use lazy_static::lazy_static;
+use sha2::Sha256;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The signature of the request, "sha256=<hex>"
+pub const SIGNATURE_HEADER: &str = "x-pokem-signature";
+
+/// Unix timestamp of when the request was signed
+pub const TIMESTAMP_HEADER: &str = "x-pokem-timestamp";
+
+/// How far the timestamp can be from the current time, in seconds
+pub const REPLAY_WINDOW: i64 = 5 * 60;
+
+lazy_static! {
+    /// Signatures accepted within the replay window, with their timestamps
+    static ref SEEN: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+}
+
+/// Check if the request has a signature
+pub fn is_signed(headers: &HeaderMap) -> bool {
+    headers.contains_key(SIGNATURE_HEADER)
+}
+
+/// Keep the headers that are used for a signed request.
+///
+/// Headers aren't signed, so they could be changed in transit. Only the content type is kept
+/// to read the body, the options have to be in the signed query or body.
+pub fn signed_headers(headers: &HeaderMap) -> HeaderMap {
+    headers
+        .iter()
+        .filter(|(name, _)| *name == CONTENT_TYPE)
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Verify the signature of a request.
+///
+/// The signature is the HMAC-SHA256 with the secret of the timestamp, method, path with the
+/// query, and body, each on its own line. The timestamp must be within the replay window, and
+/// each signature is only accepted once.
+pub fn verify(
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: &[u8],
+    secret: &str,
+) -> anyhow::Result<()> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| anyhow::anyhow!("Missing the {} header", name))
+    };
+    let signature = header(SIGNATURE_HEADER)?;
+    let timestamp = header(TIMESTAMP_HEADER)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let time: i64 = timestamp
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid timestamp: {}", timestamp))?;
+    if now.abs_diff(time) > REPLAY_WINDOW as u64 {
+        return Err(anyhow::anyhow!("The signature has expired"));
+    }
+
+    let Ok(expected) = hex::decode(signature.trim().trim_start_matches("sha256=")) else {
+        return Err(anyhow::anyhow!("Invalid signature"));
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    let path = uri
+        .path_and_query()
+        .map_or(uri.path(), |path| path.as_str());
+    mac.update(format!("{}\n{}\n{}\n", timestamp.trim(), method, path).as_bytes());
+    mac.update(body);
+    if mac.verify_slice(&expected).is_err() {
+        return Err(anyhow::anyhow!("Invalid signature"));
+    }
+
+    // Reject the same request being sent again within the window
+    let mut seen = SEEN.lock().unwrap();
+    seen.retain(|_, time| now.abs_diff(*time) <= REPLAY_WINDOW as u64);
+    if seen.insert(hex::encode(expected), time).is_some() {
+        return Err(anyhow::anyhow!("The request was already received"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    /// Build the headers for a request signed at `timestamp`
+    fn sign(timestamp: &str, method: &str, path: &str, body: &[u8], secret: &str) -> HeaderMap {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}\n{}\n{}\n", timestamp, method, path).as_bytes());
+        mac.update(body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SIGNATURE_HEADER,
+            format!("sha256={}", signature).parse().unwrap(),
+        );
+        headers.insert(TIMESTAMP_HEADER, timestamp.parse().unwrap());
+        headers
+    }
+
+    fn check(headers: &HeaderMap, path: &str, body: &[u8]) -> anyhow::Result<()> {
+        verify(&Method::POST, &path.parse().unwrap(), headers, body, SECRET)
+    }
+
+    #[test]
+    fn valid_signature() {
+        let headers = sign(
+            &now().to_string(),
+            "POST",
+            "/room?priority=5",
+            b"valid",
+            SECRET,
+        );
+        assert!(check(&headers, "/room?priority=5", b"valid").is_ok());
+    }
+
+    #[test]
+    fn wrong_signature() {
+        let timestamp = now().to_string();
+        let headers = sign(&timestamp, "POST", "/room", b"wrong", "other secret");
+        assert!(check(&headers, "/room", b"wrong").is_err());
+        // The body, path and method are all signed
+        let headers = sign(&timestamp, "POST", "/room", b"wrong body", SECRET);
+        assert!(check(&headers, "/room", b"changed body").is_err());
+        assert!(check(&headers, "/other", b"wrong body").is_err());
+        let headers = sign(&timestamp, "PUT", "/room", b"wrong method", SECRET);
+        assert!(check(&headers, "/room", b"wrong method").is_err());
+    }
+
+    #[test]
+    fn expired_timestamp() {
+        let timestamp = (now() - REPLAY_WINDOW - 1).to_string();
+        let headers = sign(&timestamp, "POST", "/room", b"expired", SECRET);
+        assert!(check(&headers, "/room", b"expired").is_err());
+        let timestamp = (now() + REPLAY_WINDOW + 60).to_string();
+        let headers = sign(&timestamp, "POST", "/room", b"future", SECRET);
+        assert!(check(&headers, "/room", b"future").is_err());
+    }
+
+    #[test]
+    fn replayed_request() {
+        let headers = sign(&now().to_string(), "POST", "/room", b"replayed", SECRET);
+        assert!(check(&headers, "/room", b"replayed").is_ok());
+        assert!(check(&headers, "/room", b"replayed").is_err());
+    }
+
+    #[test]
+    fn malformed_timestamp() {
+        for timestamp in [
+            "",
+            "soon",
+            "1.5",
+            &i64::MIN.to_string(),
+            &i64::MAX.to_string(),
+            "99999999999999999999999",
+        ] {
+            let headers = sign(timestamp, "POST", "/room", b"malformed", SECRET);
+            assert!(check(&headers, "/room", b"malformed").is_err());
+        }
+        let mut headers = sign(&now().to_string(), "POST", "/room", b"missing", SECRET);
+        headers.remove(TIMESTAMP_HEADER);
+        assert!(check(&headers, "/room", b"missing").is_err());
+    }
+}
@@ -0,0 +1,297 @@
+/// Subscribe to the messages in a room over HTTP
+use headjack::Bot;
+use lazy_static::lazy_static;
+
+use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use matrix_sdk::Room;
+
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::debug;
+
+use crate::utils::{is_secret_command, parse_duration};
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::{Response, StatusCode};
+
+/// How often to send a keepalive to subscribers
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+/// Max number of messages cached per room
+const CACHE_SIZE: usize = 100;
+
+/// How long messages are cached for
+const CACHE_DURATION: Duration = Duration::from_secs(12 * 60 * 60);
+
+lazy_static! {
+    /// Every message the bot sees, sent to all the active subscriptions
+    static ref MESSAGES: broadcast::Sender<(OwnedRoomId, StreamMessage)> = broadcast::channel(256).0;
+    /// The recent messages in each room, for polling
+    static ref CACHE: Mutex<HashMap<OwnedRoomId, VecDeque<StreamMessage>>> = Mutex::new(HashMap::new());
+}
+
+/// A single event in a subscription, using ntfy's JSON message format
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamMessage {
+    pub id: String,
+    pub time: u64,
+    pub event: String,
+    pub topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+}
+
+impl StreamMessage {
+    /// Create a non-message event, e.g. "open" or "keepalive"
+    fn control(event: &str, topic: &str) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        StreamMessage {
+            id: format!("{}", now.as_millis()),
+            time: now.as_secs(),
+            event: event.to_string(),
+            topic: topic.to_string(),
+            message: None,
+            sender: None,
+        }
+    }
+}
+
+/// Which cached messages to send, from the `since` query parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum Since {
+    /// Every cached message
+    All,
+    /// Messages sent after this event id
+    EventId(String),
+    /// Messages sent at or after this unix timestamp
+    Time(u64),
+}
+
+impl Since {
+    /// Parse "all", a unix timestamp, a duration like "10m" or an event id
+    pub fn parse(since: &str) -> Self {
+        if since == "all" {
+            Since::All
+        } else if let Ok(timestamp) = since.parse() {
+            Since::Time(timestamp)
+        } else if let Some(duration) = parse_duration(since) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            Since::Time(now.saturating_sub(duration).as_secs())
+        } else {
+            Since::EventId(since.to_string())
+        }
+    }
+}
+
+/// Get the cached messages in a room
+fn cached_messages(room_id: &RoomId, since: &Since) -> Vec<StreamMessage> {
+    let cache = CACHE.lock().unwrap();
+    let Some(messages) = cache.get(room_id) else {
+        return Vec::new();
+    };
+    match since {
+        Since::All => messages.iter().cloned().collect(),
+        Since::Time(time) => messages
+            .iter()
+            .filter(|m| m.time >= *time)
+            .cloned()
+            .collect(),
+        Since::EventId(id) => match messages.iter().position(|m| &m.id == id) {
+            Some(position) => messages.iter().skip(position + 1).cloned().collect(),
+            // Unknown ids may have expired from the cache, so send everything
+            None => messages.iter().cloned().collect(),
+        },
+    }
+}
+
+/// Add a message to the cache, dropping old messages
+fn cache_message(room_id: &RoomId, message: StreamMessage) {
+    let mut cache = CACHE.lock().unwrap();
+    let messages = cache.entry(room_id.to_owned()).or_default();
+    messages.push_back(message);
+    let oldest = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .saturating_sub(CACHE_DURATION)
+        .as_secs();
+    while messages.len() > CACHE_SIZE || messages.front().is_some_and(|m| m.time < oldest) {
+        messages.pop_front();
+    }
+}
+
+/// The output format of a subscription
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamFormat {
+    /// Newline delimited JSON
+    Json,
+    /// Server-Sent Events
+    Sse,
+    /// Only the message text, one message per line
+    Raw,
+}
+
+impl StreamFormat {
+    /// Split a subscription path like "room/json" into the room and format
+    pub fn from_path(path: &str) -> Option<(&str, Self)> {
+        let (room, format) = path.rsplit_once('/')?;
+        let format = match format {
+            "json" => StreamFormat::Json,
+            "sse" => StreamFormat::Sse,
+            "raw" => StreamFormat::Raw,
+            _ => return None,
+        };
+        Some((room, format))
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            StreamFormat::Json => "application/x-ndjson; charset=utf-8",
+            StreamFormat::Sse => "text/event-stream; charset=utf-8",
+            StreamFormat::Raw => "text/plain; charset=utf-8",
+        }
+    }
+
+    /// Render a single event, returns None if this format skips the event
+    fn render(&self, message: &StreamMessage) -> Option<String> {
+        match self {
+            StreamFormat::Json => Some(format!("{}\n", serde_json::to_string(message).ok()?)),
+            StreamFormat::Sse => {
+                let data = serde_json::to_string(message).ok()?;
+                if message.event == "message" {
+                    Some(format!("id: {}\ndata: {}\n\n", message.id, data))
+                } else {
+                    Some(format!("event: {}\ndata: {}\n\n", message.event, data))
+                }
+            }
+            StreamFormat::Raw => match message.event.as_str() {
+                "message" => Some(format!(
+                    "{}\n",
+                    message
+                        .message
+                        .clone()
+                        .unwrap_or_default()
+                        .replace('\n', " ")
+                )),
+                "keepalive" => Some("\n".to_string()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Forward every message the bot sees to the subscriptions
+pub fn register_message_handler(bot: &Bot) {
+    bot.client().add_event_handler(
+        |event: OriginalSyncRoomMessageEvent, room: Room| async move {
+            // They are redacted, don't pass on the secret
+            if is_secret_command(event.content.body()) {
+                return;
+            }
+            let message = StreamMessage {
+                id: event.event_id.to_string(),
+                time: event.origin_server_ts.as_secs().into(),
+                event: "message".to_string(),
+                topic: room.room_id().to_string(),
+                message: Some(event.content.body().to_string()),
+                sender: Some(event.sender.to_string()),
+            };
+            cache_message(room.room_id(), message.clone());
+            // Errors only mean that nobody is subscribed
+            let _ = MESSAGES.send((room.room_id().to_owned(), message));
+        },
+    );
+}
+
+/// Send the cached messages in the room and close the connection
+pub fn poll(
+    room: Room,
+    format: StreamFormat,
+    since: Since,
+) -> Response<BoxBody<Bytes, Infallible>> {
+    let body: String = cached_messages(room.room_id(), &since)
+        .iter()
+        .filter_map(|message| format.render(message))
+        .collect();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", format.content_type())
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Full::new(Bytes::from(body)).boxed())
+        .unwrap()
+}
+
+/// Stream the messages in the room to the client until it disconnects
+///
+/// If `since` is set, the matching cached messages are sent first.
+pub fn subscribe(
+    room: Room,
+    format: StreamFormat,
+    since: Option<Since>,
+) -> Response<BoxBody<Bytes, Infallible>> {
+    let (tx, rx) = mpsc::channel::<Result<Frame<Bytes>, Infallible>>(16);
+    let mut messages = MESSAGES.subscribe();
+    let topic = room.room_id().to_string();
+    let cached = since
+        .map(|since| cached_messages(room.room_id(), &since))
+        .unwrap_or_default();
+
+    tokio::task::spawn(async move {
+        let send = |message: StreamMessage| {
+            let tx = tx.clone();
+            async move {
+                match format.render(&message) {
+                    Some(data) => tx.send(Ok(Frame::data(Bytes::from(data)))).await.is_ok(),
+                    None => true,
+                }
+            }
+        };
+        if !send(StreamMessage::control("open", &topic)).await {
+            return;
+        }
+        for message in cached {
+            if !send(message).await {
+                return;
+            }
+        }
+        let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+        // The first tick completes immediately
+        keepalive.tick().await;
+        loop {
+            let message = tokio::select! {
+                received = messages.recv() => match received {
+                    Ok((room_id, message)) if room_id == room.room_id() => message,
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        debug!("Subscription to {} skipped {} messages", topic, skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+                _ = keepalive.tick() => StreamMessage::control("keepalive", &topic),
+            };
+            if !send(message).await {
+                // The client has disconnected
+                debug!("Subscription to {} closed", topic);
+                return;
+            }
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", format.content_type())
+        .header("Access-Control-Allow-Origin", "*")
+        .body(StreamBody::new(ReceiverStream::new(rx)).boxed())
+        .unwrap()
+}
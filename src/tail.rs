@@ -0,0 +1,170 @@
+/// Stream lines from a file or stdin as batched messages
+use clap::Args;
+use regex::Regex;
+
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncSeekExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::error;
+
+use crate::utils::parse_duration;
+
+/// How often to check a file for new lines
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Options for batching streamed lines
+#[derive(Args, Clone)]
+#[command(next_help_heading = "Streaming")]
+pub struct StreamArgs {
+    /// How long to collect lines before sending them, e.g. "5s" or "1m"
+    #[arg(long, default_value = "5s", value_parser = parse_window)]
+    pub window: Duration,
+
+    /// Max number of lines in a single message
+    #[arg(long, default_value_t = 20)]
+    pub max_lines: usize,
+
+    /// Only send lines matching this regex
+    #[arg(long, value_parser = Regex::new)]
+    pub filter: Option<Regex>,
+}
+
+fn parse_window(window: &str) -> Result<Duration, String> {
+    parse_duration(window).ok_or(format!("Invalid duration: {}", window))
+}
+
+/// Read lines until EOF and send them to the channel
+pub fn read_lines(reader: impl AsyncRead + Unpin + Send + 'static) -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel(1024);
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        while let Ok(read) = reader.read_until(b'\n', &mut line).await {
+            if read == 0 || tx.send(lossy_line(&line)).await.is_err() {
+                break;
+            }
+            line.clear();
+        }
+    });
+    rx
+}
+
+/// Follow a file like `tail -f`, sending new lines to the channel.
+///
+/// Starts at the end of the file, and starts over if the file is truncated or replaced.
+pub fn follow_file(path: PathBuf) -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel(1024);
+    tokio::spawn(async move {
+        let mut position = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                error!("Failed to read {}: {:?}", path.display(), e);
+                return;
+            }
+        };
+        loop {
+            let mut file = match File::open(&path).await {
+                Ok(file) => file,
+                Err(_) => {
+                    // The file may be in the middle of being rotated
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    continue;
+                }
+            };
+            if file.seek(SeekFrom::Start(position)).await.is_err() {
+                position = 0;
+                continue;
+            }
+            let mut reader = BufReader::new(file);
+            let mut line = Vec::new();
+            loop {
+                match reader.read_until(b'\n', &mut line).await {
+                    Ok(0) | Err(_) => {
+                        // Wait for a full line
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                        let len = tokio::fs::metadata(&path)
+                            .await
+                            .map(|m| m.len())
+                            .unwrap_or(0);
+                        if len < position {
+                            // Truncated or replaced, start again from the beginning
+                            position = 0;
+                            break;
+                        }
+                    }
+                    Ok(read) if line.ends_with(b"\n") => {
+                        position += read as u64;
+                        if tx.send(lossy_line(&line)).await.is_err() {
+                            return;
+                        }
+                        line.clear();
+                    }
+                    Ok(read) => {
+                        // Partial line, the rest hasn't been written yet
+                        position += read as u64;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
+
+fn lossy_line(line: &[u8]) -> String {
+    String::from_utf8_lossy(line).trim_end().to_string()
+}
+
+/// Collect the lines into batches and send each batch.
+///
+/// A batch is sent once the window has passed since its first line, or once it is full.
+pub async fn send_batches<F, Fut>(
+    mut lines: mpsc::Receiver<String>,
+    options: &StreamArgs,
+    mut send: F,
+) where
+    F: FnMut(Vec<String>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut batch = Vec::new();
+    let mut deadline = None;
+    loop {
+        let line = match deadline {
+            Some(batch_deadline) => tokio::select! {
+                line = lines.recv() => line,
+                _ = tokio::time::sleep_until(batch_deadline) => {
+                    send(std::mem::take(&mut batch)).await;
+                    deadline = None;
+                    continue;
+                }
+            },
+            None => lines.recv().await,
+        };
+        let Some(line) = line else {
+            break;
+        };
+        if options
+            .filter
+            .as_ref()
+            .is_some_and(|filter| !filter.is_match(&line))
+        {
+            continue;
+        }
+        if batch.is_empty() {
+            deadline = Some(Instant::now() + options.window);
+        }
+        batch.push(line);
+        if batch.len() >= options.max_lines {
+            send(std::mem::take(&mut batch)).await;
+            deadline = None;
+        }
+    }
+    if !batch.is_empty() {
+        send(batch).await;
+    }
+}
@@ -0,0 +1,490 @@
+/// Named authentication tokens for a room, stored as salted hashes in the room account data,
+/// and the room's secret for signed requests
+use crate::utils::{parse_duration, MessageRelation};
+
+use chrono::{Days, Local, NaiveDate, TimeDelta, TimeZone};
+use lazy_static::lazy_static;
+use matrix_sdk::ruma::api::client::config::set_room_account_data;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::events::{AnyRoomAccountDataEventContent, RoomAccountDataEventType};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId, UserId};
+use matrix_sdk::Room;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The room account data type holding the tokens
+const TOKENS_EVENT_TYPE: &str = "dev.pokem.tokens";
+
+/// The room account data type holding the secret
+const SECRET_EVENT_TYPE: &str = "dev.pokem.secret";
+
+/// Length of a generated token
+const TOKEN_LENGTH: usize = 32;
+
+/// The window for the rate limits
+const RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+lazy_static! {
+    /// When each rate limited token was used in the last hour, keyed by the token's salt
+    static ref USAGE: Mutex<HashMap<String, Vec<Instant>>> = Mutex::new(HashMap::new());
+    /// Account data saved by the bot since it started, the next sync may not have it yet.
+    /// Only the bot writes its own account data, so it stays current.
+    static ref SAVED: Mutex<HashMap<(OwnedRoomId, &'static str), serde_json::Value>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Restrictions on what can be sent with a token
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TokenScope {
+    /// Unix timestamp after which the token is no longer accepted
+    pub expires: Option<i64>,
+    /// Most pokes in an hour
+    pub rate: Option<u32>,
+    /// Highest priority that can be sent
+    pub max_priority: Option<u8>,
+    /// Don't allow mentioning the whole @room
+    #[serde(default)]
+    pub no_mention: bool,
+    /// Only send in this thread
+    pub thread: Option<OwnedEventId>,
+}
+
+impl TokenScope {
+    /// Parse the options for `token create`, e.g. "expires=2025-12-31 rate=10 no-mention"
+    fn parse(options: &[&str]) -> Result<Self, String> {
+        let mut scope = TokenScope::default();
+        for option in options {
+            match option.split_once('=') {
+                Some(("expires", value)) => scope.expires = Some(parse_expiry(value)?),
+                Some(("rate", value)) => {
+                    scope.rate = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("Invalid rate: {}", value))?,
+                    )
+                }
+                Some(("priority", value)) => {
+                    scope.max_priority = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|priority| (1..=5).contains(priority))
+                            .ok_or_else(|| format!("Invalid priority: {}", value))?,
+                    )
+                }
+                Some(("thread", value)) => {
+                    scope.thread = Some(
+                        EventId::parse(value)
+                            .map_err(|_| format!("Invalid event ID: {}", value))?,
+                    )
+                }
+                None if *option == "no-mention" => scope.no_mention = true,
+                _ => return Err(format!("Unknown option: {}", option)),
+            }
+        }
+        Ok(scope)
+    }
+
+    /// Check if the token is limited in what it can send.
+    ///
+    /// Restricted tokens can't read the room, an expiry alone isn't a restriction.
+    pub fn is_restricted(&self) -> bool {
+        self.rate.is_some()
+            || self.max_priority.is_some()
+            || self.no_mention
+            || self.thread.is_some()
+    }
+
+    /// Describe the restrictions for listing tokens
+    fn describe(&self) -> String {
+        let mut restrictions = Vec::new();
+        if let Some(expires) = self.expires {
+            let expires = Local
+                .timestamp_opt(expires, 0)
+                .single()
+                .map(|expires| expires.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            restrictions.push(format!("expires {}", expires));
+        }
+        if let Some(rate) = self.rate {
+            restrictions.push(format!("{} pokes per hour", rate));
+        }
+        if let Some(priority) = self.max_priority {
+            restrictions.push(format!("priority up to {}", priority));
+        }
+        if self.no_mention {
+            restrictions.push("no @room mentions".to_string());
+        }
+        if let Some(thread) = &self.thread {
+            restrictions.push(format!("only in thread {}", thread));
+        }
+        restrictions.join(", ")
+    }
+}
+
+/// Parse when a token expires, either a duration like "30d" or the last day like "2025-12-31"
+fn parse_expiry(expires: &str) -> Result<i64, String> {
+    if let Some(duration) = parse_duration(expires) {
+        return TimeDelta::from_std(duration)
+            .ok()
+            .and_then(|duration| Local::now().checked_add_signed(duration))
+            .map(|date| date.timestamp())
+            .ok_or_else(|| format!("Invalid expiry: {}", expires));
+    }
+    NaiveDate::parse_from_str(expires, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.checked_add_days(Days::new(1)))
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|date| date.and_local_timezone(Local).earliest())
+        .map(|date| date.timestamp())
+        .ok_or_else(|| format!("Invalid expiry: {}", expires))
+}
+
+/// An authentication token for a room.
+///
+/// Only the salted hash is stored, the token itself is shown once when it is created.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthToken {
+    pub name: String,
+    salt: String,
+    hash: String,
+    /// Unix timestamp of when it was created
+    pub created: i64,
+    #[serde(default)]
+    pub scope: TokenScope,
+}
+
+impl AuthToken {
+    pub fn new(name: &str, token: &str) -> Self {
+        let salt = hex::encode(rand::random::<[u8; 16]>());
+        AuthToken {
+            name: name.to_string(),
+            hash: hash(&salt, token),
+            salt,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default(),
+            scope: TokenScope::default(),
+        }
+    }
+
+    /// Check if this is the token
+    pub fn matches(&self, token: &str) -> bool {
+        !token.is_empty() && hash(&self.salt, token) == self.hash
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.scope
+            .expires
+            .is_some_and(|expires| Local::now().timestamp() >= expires)
+    }
+
+    /// Check that a poke is allowed by the token's scope.
+    ///
+    /// The poke counts towards the rate limit once it is sent, see `count_poke`.
+    /// Returns the relation to send the poke with, pokes are moved into the token's thread.
+    pub fn authorize(
+        &self,
+        priority: Option<u8>,
+        mention_room: bool,
+        relation: Option<MessageRelation>,
+    ) -> anyhow::Result<Option<MessageRelation>> {
+        let scope = &self.scope;
+        if let Some(max_priority) = scope.max_priority {
+            if priority.is_some_and(|priority| priority > max_priority) {
+                return Err(anyhow::anyhow!(
+                    "The token can't send pokes above priority {}",
+                    max_priority
+                ));
+            }
+        }
+        if scope.no_mention && mention_room {
+            return Err(anyhow::anyhow!("The token can't mention the @room"));
+        }
+        let relation = match (&scope.thread, relation) {
+            (Some(thread), None) => Some(MessageRelation::Thread(thread.clone())),
+            (Some(thread), Some(MessageRelation::Thread(event_id))) if event_id == *thread => {
+                Some(MessageRelation::Thread(event_id))
+            }
+            (Some(thread), Some(_)) => {
+                return Err(anyhow::anyhow!(
+                    "The token can only send in thread {}",
+                    thread
+                ));
+            }
+            (None, relation) => relation,
+        };
+        if let Some(rate) = scope.rate {
+            let mut usage = USAGE.lock().unwrap();
+            let used = usage.entry(self.salt.clone()).or_default();
+            used.retain(|time| time.elapsed() < RATE_WINDOW);
+            if used.len() >= rate as usize {
+                return Err(anyhow::anyhow!(
+                    "The token can only send {} pokes per hour",
+                    rate
+                ));
+            }
+        }
+        Ok(relation)
+    }
+
+    /// The key to count the token's pokes with, if it is rate limited
+    pub fn rate_key(&self) -> Option<String> {
+        self.scope.rate.map(|_| self.salt.clone())
+    }
+}
+
+/// Count a poke that was sent towards its token's rate limit
+pub fn count_poke(rate_key: &str) {
+    USAGE
+        .lock()
+        .unwrap()
+        .entry(rate_key.to_string())
+        .or_default()
+        .push(Instant::now());
+}
+
+fn hash(salt: &str, token: &str) -> String {
+    hex::encode(
+        Sha256::new()
+            .chain_update(salt)
+            .chain_update(token)
+            .finalize(),
+    )
+}
+
+/// Generate a new random token
+pub fn generate_token() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH)
+}
+
+/// The content of the room account data
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TokenList {
+    tokens: Vec<AuthToken>,
+}
+
+/// The room's secret for signed requests and webhooks, in the room account data
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RoomSecret {
+    secret: Option<String>,
+}
+
+/// A room account data event from the local store
+#[derive(Debug, Deserialize)]
+struct AccountDataEvent<T> {
+    content: T,
+}
+
+/// Load the tokens for the room.
+///
+/// They are the tokens the bot saved since it started, or the copy from the last sync.
+/// Fails if the synced copy can't be read, so the room isn't left without tokens.
+pub async fn load_tokens(room: &Room) -> anyhow::Result<Vec<AuthToken>> {
+    Ok(load_data::<TokenList>(room, TOKENS_EVENT_TYPE)
+        .await?
+        .tokens)
+}
+
+/// Replace the tokens for the room
+pub async fn save_tokens(room: &Room, tokens: Vec<AuthToken>) -> anyhow::Result<()> {
+    save_data(room, TOKENS_EVENT_TYPE, &TokenList { tokens }).await
+}
+
+/// Load the room's secret, the same way as the tokens
+pub async fn load_secret(room: &Room) -> anyhow::Result<Option<String>> {
+    Ok(load_data::<RoomSecret>(room, SECRET_EVENT_TYPE)
+        .await?
+        .secret)
+}
+
+/// Replace the room's secret
+pub async fn save_secret(room: &Room, secret: Option<String>) -> anyhow::Result<()> {
+    save_data(room, SECRET_EVENT_TYPE, &RoomSecret { secret }).await
+}
+
+/// Get the room account data the bot saved since it started, or the copy from the last sync
+async fn load_data<T: DeserializeOwned + Default>(
+    room: &Room,
+    event_type: &'static str,
+) -> anyhow::Result<T> {
+    let saved = SAVED
+        .lock()
+        .unwrap()
+        .get(&(room.room_id().to_owned(), event_type))
+        .cloned();
+    if let Some(content) = saved {
+        return Ok(serde_json::from_value(content)?);
+    }
+    match room
+        .account_data(RoomAccountDataEventType::from(event_type))
+        .await?
+    {
+        Some(event) => Ok(event.deserialize_as::<AccountDataEvent<T>>()?.content),
+        // It has never been set
+        None => Ok(T::default()),
+    }
+}
+
+/// Replace the room account data
+async fn save_data<T: Serialize>(
+    room: &Room,
+    event_type: &'static str,
+    content: &T,
+) -> anyhow::Result<()> {
+    let client = room.client();
+    let Some(user_id) = client.user_id() else {
+        return Err(anyhow::anyhow!("Not logged in"));
+    };
+    let content = serde_json::to_value(content)?;
+    let data = Raw::new(&content)?.cast::<AnyRoomAccountDataEventContent>();
+    let request = set_room_account_data::v3::Request::new_raw(
+        user_id.to_owned(),
+        room.room_id().to_owned(),
+        RoomAccountDataEventType::from(event_type),
+        data,
+    );
+    client.send(request, None).await?;
+    SAVED
+        .lock()
+        .unwrap()
+        .insert((room.room_id().to_owned(), event_type), content);
+    Ok(())
+}
+
+/// Find the direct message room with the user, or create one
+async fn direct_room(room: &Room, user: &UserId) -> anyhow::Result<Room> {
+    let client = room.client();
+    let existing = client.joined_rooms().into_iter().find(|r| {
+        let targets = r.direct_targets();
+        targets.len() == 1 && targets.contains(user)
+    });
+    match existing {
+        Some(dm) => Ok(dm),
+        None => Ok(client.create_dm(user).await?),
+    }
+}
+
+/// Handle `!pokem token ...`, and respond with the result
+///
+/// New tokens are sent to `sender` in a direct message, so they aren't left in the room.
+pub async fn token_command(room: &Room, sender: &UserId, args: &[&str]) -> String {
+    let mut tokens = match load_tokens(room).await {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            error!("Failed to load the tokens: {:?}", e);
+            return "ERROR: Failed to read the tokens".to_string();
+        }
+    };
+    match args {
+        ["create", name, options @ ..] => {
+            if tokens.iter().any(|token| token.name == *name) {
+                return format!(
+                    "There is already a token named {}, revoke it first to replace it",
+                    name
+                );
+            }
+            let scope = match TokenScope::parse(options) {
+                Ok(scope) => scope,
+                Err(e) => return e,
+            };
+            let dm = match direct_room(room, sender).await {
+                Ok(dm) => dm,
+                Err(e) => {
+                    error!(
+                        "Failed to get a direct message room with {}: {:?}",
+                        sender, e
+                    );
+                    return "ERROR: Failed to start a direct message to send the token in"
+                        .to_string();
+                }
+            };
+            let token = generate_token();
+            let mut auth_token = AuthToken::new(name, &token);
+            auth_token.scope = scope;
+            tokens.push(auth_token);
+            if let Err(e) = save_tokens(room, tokens.clone()).await {
+                error!("Failed to save the tokens: {:?}", e);
+                return "ERROR: Failed to save the token".to_string();
+            }
+            let room_name = room
+                .canonical_alias()
+                .map(|alias| alias.to_string())
+                .unwrap_or_else(|| room.room_id().to_string());
+            let message = format!(
+                "Created token {} for {}: `{}`\n\nIt won't be shown again.",
+                name, room_name, token
+            );
+            if let Err(e) = dm
+                .send(RoomMessageEventContent::text_markdown(message))
+                .await
+            {
+                error!("Failed to send the token to {}: {:?}", sender, e);
+                // Nobody has seen it, so don't leave it usable
+                tokens.retain(|token| token.name != *name);
+                if let Err(e) = save_tokens(room, tokens).await {
+                    error!("Failed to remove the unsent token: {:?}", e);
+                }
+                return "ERROR: Failed to send the token in a direct message".to_string();
+            }
+            format!(
+                "Created token {}, it was sent to you in a direct message",
+                name
+            )
+        }
+        ["list"] => {
+            if tokens.is_empty() {
+                "There are no tokens, anyone can poke this room".to_string()
+            } else {
+                let names: Vec<String> = tokens
+                    .iter()
+                    .map(|token| {
+                        let restrictions = token.scope.describe();
+                        if token.is_expired() {
+                            format!("- {} (expired)", token.name)
+                        } else if restrictions.is_empty() {
+                            format!("- {}", token.name)
+                        } else {
+                            format!("- {}: {}", token.name, restrictions)
+                        }
+                    })
+                    .collect();
+                format!("Tokens:\n{}", names.join("\n"))
+            }
+        }
+        ["revoke", name] => {
+            let before = tokens.len();
+            tokens.retain(|token| token.name != *name);
+            if tokens.len() == before {
+                return format!("There is no token named {}", name);
+            }
+            match save_tokens(room, tokens).await {
+                Ok(()) => format!("Revoked token {}", name),
+                Err(e) => {
+                    error!("Failed to save the tokens: {:?}", e);
+                    "ERROR: Failed to revoke the token".to_string()
+                }
+            }
+        }
+        _ => "Usage:\n\
+            - `token create <name> [options]`, the token is sent to you once in a direct message\n\
+            - `token list`\n\
+            - `token revoke <name>`\n\n\
+            Options restrict what the token can send:\n\
+            - `expires=<date|duration>`, e.g. `expires=2025-12-31` or `expires=30d`\n\
+            - `rate=<pokes>`, the most pokes in an hour\n\
+            - `priority=<1-5>`, the highest priority\n\
+            - `no-mention`, don't allow mentioning the @room\n\
+            - `thread=<event id>`, only send in this thread"
+            .to_string(),
+    }
+}
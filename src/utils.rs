@@ -1,18 +1,40 @@
 /// Common utils for pok'em
+use crate::attachment::Attachment;
 use crate::config::*;
+use crate::token::{count_poke, load_secret, load_tokens, save_secret, save_tokens, AuthToken};
 use headjack::*;
 
-use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::events::relation::{InReplyTo, Thread};
+use matrix_sdk::ruma::events::room::message::{
+    Relation, ReplacementMetadata, RoomMessageEventContent,
+};
 
 use matrix_sdk::ruma::events::tag::TagInfo;
 use matrix_sdk::ruma::events::Mentions;
+use matrix_sdk::ruma::OwnedEventId;
 use matrix_sdk::{Room, RoomMemberships, RoomState};
 
+use serde::{Deserialize, Serialize};
 use tracing::{error, info};
 
 use hyper::HeaderMap;
 
-/// Write the Room config into the tags
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use chrono::{Days, Local, NaiveTime};
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the token set with `set auth <token>`
+pub const DEFAULT_TOKEN_NAME: &str = "default";
+
+/// Write the Room config into the tags, and the secret into the account data.
+///
+/// The tokens are saved separately with `save_tokens`, and any old plaintext tokens and
+/// secret tags are removed.
 pub async fn set_room_config(room: &Room, config: RoomConfig) {
     if config.block {
         room.set_tag("dev.pokem.block".into(), TagInfo::default())
@@ -21,85 +43,125 @@ pub async fn set_room_config(room: &Room, config: RoomConfig) {
     } else {
         room.remove_tag("dev.pokem.block".into()).await.unwrap();
     }
-    // Grab the auth token from the option for ergonomics
-    let auth_token = config.auth.clone().unwrap_or("".to_string());
-    // Remove any existing auth token
-    let mut placed = false;
+    if config.signing {
+        room.set_tag("dev.pokem.signing".into(), TagInfo::default())
+            .await
+            .unwrap();
+    } else {
+        room.remove_tag("dev.pokem.signing".into()).await.unwrap();
+    }
+    let secret_saved = match load_secret(room).await {
+        Ok(secret) if secret == config.secret => true,
+        _ => match save_secret(room, config.secret.clone()).await {
+            Ok(()) => true,
+            Err(e) => {
+                error!(
+                    "Failed to save the secret for {}: {:?}",
+                    room.room_id().as_str(),
+                    e
+                );
+                false
+            }
+        },
+    };
+    // Remove any plaintext auth tokens, and the secret tags once the secret is saved
     let tags = room.tags().await.unwrap_or_default();
     for (tag, _) in tags.unwrap_or_default() {
-        if tag.to_string().starts_with("dev.pokem.pass.") {
-            // Old format, remove it, we'll be replacing with the new value
+        if tag.to_string().starts_with("dev.pokem.pass.")
+            || tag.to_string().starts_with("dev.pokem.auth.")
+            || (secret_saved && tag.to_string().starts_with("dev.pokem.secret."))
+        {
+            // Old format, they have been moved to the account data
             room.remove_tag(tag).await.unwrap();
-        } else if tag.to_string().starts_with("dev.pokem.auth.") {
-            if config.auth.is_some()
-                && tag.to_string().trim_start_matches("dev.pokem.auth.") == auth_token
-            {
-                // Already in place
-                placed = true;
-            } else {
-                // If this tag doesn't match the new one, remove it
-                room.remove_tag(tag).await.unwrap();
-            }
-        };
-    }
-    if config.auth.is_some() && !placed {
-        room.set_tag(
-            format!("dev.pokem.auth.{}", auth_token).into(),
-            TagInfo::default(),
-        )
-        .await
-        .unwrap();
+        }
     }
 }
 
-// Get all the current set room configs from the tags.
+// Get all the current set room configs from the tags and account data.
 pub async fn get_room_config(room: &Room) -> RoomConfig {
     let mut config = RoomConfig::default();
-    let tags = room.tags().await.unwrap_or_default();
-    let mut should_update = false;
-    for (tag, _) in tags.unwrap_or_default() {
-        if tag.to_string() == "dev.pokem.block" {
+    match load_tokens(room).await {
+        Ok(tokens) => config.tokens = tokens,
+        Err(e) => {
+            error!("Failed to load the tokens for {}: {:?}", room.room_id(), e);
+            config.unavailable = true;
+        }
+    }
+    match load_secret(room).await {
+        Ok(secret) => config.secret = secret,
+        Err(e) => {
+            error!("Failed to load the secret for {}: {:?}", room.room_id(), e);
+            config.unavailable = true;
+        }
+    }
+    let tags = match room.tags().await {
+        Ok(tags) => tags.unwrap_or_default(),
+        Err(e) => {
+            error!("Failed to load the tags for {}: {:?}", room.room_id(), e);
+            config.unavailable = true;
+            Default::default()
+        }
+    };
+    let mut legacy_token = None;
+    let mut legacy_secret = None;
+    for (tag, _) in tags {
+        let tag = tag.to_string();
+        if tag == "dev.pokem.block" {
             config.block = true;
-        } else if tag.to_string().starts_with("dev.pokem.auth.") {
-            if config.auth.is_some() {
-                // We only want one auth token, this is a warning
-                // It probably means we failed to remove a token on a change
+        } else if tag == "dev.pokem.signing" {
+            config.signing = true;
+        } else if let Some(secret) = tag.strip_prefix("dev.pokem.secret.") {
+            // Old format, it will be moved to the account data
+            legacy_secret = Some(secret.to_string());
+        } else if let Some(token) = tag
+            .strip_prefix("dev.pokem.auth.")
+            .or_else(|| tag.strip_prefix("dev.pokem.pass."))
+        {
+            // TODO(2.0): Remove this in 2.0
+            // Old plaintext format, support for now
+            // It will be hashed and removed immediately
+            if legacy_token.is_some() {
                 error!(
                     "Multiple Auth Tokens set for room: {}",
                     room.room_id().as_str()
                 );
                 continue;
             }
-            // Get the auth token
-            config.auth = Some(
-                tag.to_string()
-                    .trim_start_matches("dev.pokem.auth.")
-                    .to_string(),
-            );
-        } else if tag.to_string().starts_with("dev.pokem.pass.") {
-            // TODO(2.0): Remove this in 2.0
-            // Old format, support for now
-            // It will be removed immediately and replaced
-            should_update = true;
-            if config.auth.is_some() {
-                // We only want one password, this is a warning
-                // It probably means we failed to remove a password on a password change
+            legacy_token = Some(token.to_string());
+        }
+    }
+    if config.unavailable {
+        return config;
+    }
+    // Move the secret from its tag to the account data
+    let mut migrate = false;
+    if let Some(secret) = legacy_secret {
+        config.secret.get_or_insert(secret);
+        migrate = true;
+    }
+    // Move the plaintext token to the hashed tokens
+    if let Some(token) = legacy_token {
+        if !config.tokens.iter().any(|t| t.matches(&token)) {
+            config.tokens.retain(|t| t.name != DEFAULT_TOKEN_NAME);
+            config
+                .tokens
+                .push(AuthToken::new(DEFAULT_TOKEN_NAME, &token));
+        }
+        match save_tokens(room, config.tokens.clone()).await {
+            Ok(()) => migrate = true,
+            Err(e) => {
+                // Keep the plaintext tag until it can be hashed
                 error!(
-                    "Multiple Auth Tokens set for room: {}",
-                    room.room_id().as_str()
+                    "Failed to hash the Auth Token for {}: {:?}",
+                    room.room_id().as_str(),
+                    e
                 );
-                continue;
+                migrate = false;
             }
-            // Get the auth token
-            config.auth = Some(
-                tag.to_string()
-                    .trim_start_matches("dev.pokem.pass.")
-                    .to_string(),
-            );
         }
     }
-    // Update the settings if there are old formatted auth tokens
-    if should_update {
+    // Writing the config removes the old tags
+    if migrate {
         set_room_config(room, config.clone()).await;
     }
     config
@@ -115,6 +177,21 @@ pub fn get_command_prefix() -> String {
         .command_prefix()
 }
 
+/// Check if a message is a command that sets a token or secret.
+///
+/// They are redacted, and aren't sent to subscribers.
+pub fn is_secret_command(body: &str) -> bool {
+    let Some(command) = body.trim_start().strip_prefix(&get_command_prefix()) else {
+        return false;
+    };
+    let args: Vec<&str> = command.split_whitespace().collect();
+    matches!(
+        args.as_slice(),
+        ["set", "secret" | "auth" | "authentication" | "password" | "pass", value, ..]
+            if !value.eq_ignore_ascii_case("off")
+    )
+}
+
 /// Check if we can message the room
 pub async fn can_message_room(room: &Room) -> bool {
     // Always send to the example room
@@ -157,10 +234,10 @@ pub async fn send_help(room: &Room) {
         .await
         .expect("Failed to send message");
         let config = get_room_config(room).await;
-        if let Some(pass) = config.auth {
-            room.send(RoomMessageEventContent::text_plain(format!(
-                "This Room's Authentication token is: {}",
-                pass
+        if !config.tokens.is_empty() {
+            room.send(RoomMessageEventContent::text_markdown(format!(
+                "This Room requires an Authentication token, see `{}token list`",
+                get_command_prefix()
             )))
             .await
             .expect("Failed to send message");
@@ -168,14 +245,143 @@ pub async fn send_help(room: &Room) {
     }
 }
 
-/// Send a message to a room.
+/// How a message relates to an earlier message in the room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MessageRelation {
+    /// Edit the earlier message
+    Replaces(OwnedEventId),
+    /// Send in the thread with this root
+    Thread(OwnedEventId),
+    /// Reply to the earlier message
+    Reply(OwnedEventId),
+}
+
+impl MessageRelation {
+    /// The query parameter to send this relation to a pokem server
+    pub fn query_param(&self) -> (&'static str, String) {
+        match self {
+            MessageRelation::Replaces(event_id) => ("replaces", event_id.to_string()),
+            MessageRelation::Thread(event_id) => ("thread", event_id.to_string()),
+            MessageRelation::Reply(event_id) => ("reply_to", event_id.to_string()),
+        }
+    }
+
+    /// Add the relation to the message
+    fn apply(&self, mut msg: RoomMessageEventContent) -> RoomMessageEventContent {
+        match self {
+            MessageRelation::Replaces(event_id) => {
+                return msg
+                    .make_replacement(ReplacementMetadata::new(event_id.clone(), None), None);
+            }
+            MessageRelation::Thread(event_id) => {
+                msg.relates_to = Some(Relation::Thread(Thread::without_fallback(event_id.clone())));
+            }
+            MessageRelation::Reply(event_id) => {
+                msg.relates_to = Some(Relation::Reply {
+                    in_reply_to: InReplyTo::new(event_id.clone()),
+                });
+            }
+        }
+        msg
+    }
+}
+
+/// Sending to Matrix failed, but it may work if it's retried later
+#[derive(Debug)]
+pub struct RetryableError {
+    /// How long the homeserver asked us to wait
+    pub retry_after: Option<Duration>,
+    pub message: String,
+    /// The attachment was sent, only the message needs to be sent again
+    pub attachment_sent: bool,
+}
+
+impl std::fmt::Display for RetryableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RetryableError {}
+
+/// Add context to an error from Matrix.
+///
+/// Connection errors, server errors and rate limits are marked as a RetryableError.
+fn matrix_error(context: &str, error: anyhow::Error) -> anyhow::Error {
+    let message = format!("{}: {}", context, error);
+    let http_error = error.downcast_ref::<matrix_sdk::HttpError>().or_else(|| {
+        match error.downcast_ref::<matrix_sdk::Error>() {
+            Some(matrix_sdk::Error::Http(http_error)) => Some(http_error),
+            _ => None,
+        }
+    });
+    let Some(http_error) = http_error else {
+        return anyhow::anyhow!(message);
+    };
+    let retry_after = match (
+        http_error.as_client_api_error(),
+        http_error.client_api_error_kind(),
+    ) {
+        (_, Some(ErrorKind::LimitExceeded { retry_after_ms })) => *retry_after_ms,
+        (Some(api_error), _) if !api_error.status_code.is_server_error() => {
+            return anyhow::anyhow!(message);
+        }
+        // The homeserver couldn't be reached or had an error
+        _ => None,
+    };
+    anyhow::Error::new(RetryableError {
+        retry_after,
+        message,
+        attachment_sent: false,
+    })
+}
+
+/// Send a message to a room, after checking the authentication token.
+///
+/// If there is an attachment it is sent first, followed by the message if it isn't empty.
+/// Threads and replies apply to both, but an edit only applies to the message.
+///
+/// Returns the event id of the last event sent, or None if we aren't allowed to message the room.
 pub async fn ping_room(
     bot: &Bot,
     room_id: &str,
     headers: &HeaderMap,
     message: &str,
     mention_room: bool,
-) -> anyhow::Result<()> {
+    attachment: Option<&Attachment>,
+    relation: Option<&MessageRelation>,
+) -> anyhow::Result<Option<OwnedEventId>> {
+    let r = find_joined_room(bot, room_id).await?;
+
+    let room_config = get_room_config(&r).await;
+    if room_config.signing {
+        return Err(anyhow::anyhow!("The room requires signed requests"));
+    }
+
+    // Validate the authentication token and remove it from the message
+    let (msg, token) = validate_authentication(room_config, headers, message)?;
+    let relation = match &token {
+        Some(token) => token.authorize(None, mention_room, relation.cloned())?,
+        None => relation.cloned(),
+    };
+
+    let event_id = send_to_room(
+        &r,
+        headers,
+        &msg,
+        mention_room,
+        attachment,
+        relation.as_ref(),
+    )
+    .await?;
+    if let Some(rate_key) = token.and_then(|token| token.rate_key()) {
+        count_poke(&rate_key);
+    }
+    Ok(event_id)
+}
+
+/// Find the room, waiting for the invite to be accepted if we were just invited
+pub async fn find_joined_room(bot: &Bot, room_id: &str) -> anyhow::Result<Room> {
     let r = get_room_from_name(bot, room_id).await;
     if r.is_none() {
         error!("Failed to find room with name: {}", room_id);
@@ -195,32 +401,144 @@ pub async fn ping_room(
         tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
         delay *= 2;
     }
+    Ok(r)
+}
 
-    let mut msg: String = message.to_string();
-
-    let room_config = get_room_config(&r).await;
+/// Send a message to a room, without checking the authentication token.
+///
+/// See `ping_room`.
+pub async fn send_to_room(
+    r: &Room,
+    headers: &HeaderMap,
+    msg: &str,
+    mention_room: bool,
+    attachment: Option<&Attachment>,
+    relation: Option<&MessageRelation>,
+) -> anyhow::Result<Option<OwnedEventId>> {
+    // Get the message formatting
+    let only_attachment = msg.is_empty();
+    let mut msg = format_message(headers, msg);
+    if let Some(relation) = relation {
+        msg = relation.apply(msg);
+    }
+    // Always set the mentions, so an "@room" in the text doesn't notify the room by itself
+    let mentions = || {
+        if mention_room {
+            Mentions::with_room_mention()
+        } else {
+            Mentions::new()
+        }
+    };
+    msg = msg.add_mentions(mentions());
 
-    // Validate the authentication token and remove it from the message
-    if let Ok(cleaned_msg) = validate_authentication(room_config, headers, &msg) {
-        msg = cleaned_msg;
+    if can_message_room(r).await {
+        if let Some(attachment) = attachment {
+            let mut content = attachment
+                .upload(r)
+                .await
+                .map_err(|e| matrix_error("Failed to upload attachment", e))?;
+            if let Some(relation) =
+                relation.filter(|relation| !matches!(relation, MessageRelation::Replaces(_)))
+            {
+                content = relation.apply(content);
+            }
+            content = content.add_mentions(mentions());
+            let event_id = match r.send(content).await {
+                Ok(response) => response.event_id,
+                Err(e) => return Err(matrix_error("Failed to send attachment", e.into())),
+            };
+            if only_attachment {
+                return Ok(Some(event_id));
+            }
+        }
+        match r.send(msg).await {
+            Ok(response) => Ok(Some(response.event_id)),
+            Err(e) => {
+                let mut error = matrix_error("Failed to send message", e.into());
+                if let Some(retryable) = error.downcast_mut::<RetryableError>() {
+                    retryable.attachment_sent = attachment.is_some();
+                }
+                Err(error)
+            }
+        }
     } else {
-        return Err(anyhow::anyhow!("Incorrect Authentication Token"));
+        error!("Failed to send message");
+        Ok(None)
     }
+}
 
-    // Get the message formatting
-    let mut msg = format_message(headers, &msg);
-    if mention_room {
-        msg = msg.add_mentions(Mentions::with_room_mention());
+/// Build the full message text from the message, title and tags.
+///
+/// Tags that are emoji shortcodes are added as emojis, the rest are listed at the end.
+pub fn compose_message(message: &str, title: Option<&str>, tags: Option<&[String]>) -> String {
+    let mut message = message.to_string();
+
+    // Add title
+    if let Some(title) = title {
+        message = format!("**{title}**\n\n{message}");
     }
 
-    if can_message_room(&r).await {
-        if let Err(e) = r.send(msg).await {
-            return Err(anyhow::anyhow!("Failed to send message: {}", e));
+    // Add emojis
+    if let Some(tags) = tags {
+        let mut emojis_str = String::new();
+        let mut non_emojis = Vec::new();
+        for shortcode in tags {
+            if let Some(emoji) = emojis::get_by_shortcode(shortcode.as_str()) {
+                emojis_str.push_str(emoji.as_ref());
+            } else {
+                non_emojis.push(shortcode.as_str());
+            }
+        }
+        if !emojis_str.is_empty() {
+            message = format!("{emojis_str} {message}");
+        }
+        if !non_emojis.is_empty() {
+            message = format!("{message}\nTags: {}", non_emojis.join(", "));
         }
-    } else {
-        error!("Failed to send message");
     }
-    Ok(())
+    message
+}
+
+/// Parse a priority, either a number from 1 to 5 or a name like "high".
+pub fn parse_priority(priority: &str) -> u8 {
+    priority
+        .parse()
+        .unwrap_or_else(|_| match &priority.to_lowercase()[..] {
+            "min" => 1,
+            "low" => 2,
+            "default" => 3,
+            "high" => 4,
+            "urgent" | "max" => 5,
+            _ => 3,
+        })
+}
+
+/// Check if the priority is high enough to be urgent.
+pub fn is_urgent(priority: Option<u8>) -> bool {
+    priority.is_some_and(|p| p > 3)
+}
+
+/// Transform a room name from the config into the room id.
+///
+/// If the message is urgent and <room_name>-urgent exists, it will go there, otherwise
+/// we mention the entire @room.
+///
+/// Returns the room id and whether to mention the @room.
+pub fn resolve_room(
+    rooms: Option<&HashMap<String, String>>,
+    room: &str,
+    urgent: bool,
+) -> (String, bool) {
+    let rooms = rooms.cloned().unwrap_or_default();
+    if urgent {
+        if let Some(room_id) = rooms.get(&format!("{}-urgent", room)) {
+            return (room_id.clone(), false);
+        }
+    }
+    match rooms.get(room) {
+        Some(room_id) => (room_id.clone(), urgent),
+        None => (room.to_string(), urgent),
+    }
 }
 
 /// Get the appropriate message formatting.
@@ -314,43 +632,160 @@ pub async fn get_room_from_name(bot: &Bot, name: &str) -> Option<Room> {
     None
 }
 
-/// Validate the authentication token
+/// Get the authentication token from the headers.
+///
+/// Allows "authentication" and "auth", or the standard "authorization" header with a Bearer
+/// token or Basic auth. With Basic auth the token is the password, or the username if there is
+/// no password.
+pub fn header_token(headers: &HeaderMap) -> Option<String> {
+    if let Some(auth) = headers
+        .get("authentication")
+        .or_else(|| headers.get("auth"))
+    {
+        return auth.to_str().ok().map(String::from);
+    }
+    let authorization = headers.get("authorization")?.to_str().ok()?;
+    let (scheme, credentials) = authorization.trim().split_once(' ')?;
+    match scheme.to_lowercase().as_str() {
+        "bearer" => Some(credentials.trim().to_string()),
+        "basic" => {
+            let decoded = STANDARD.decode(credentials.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (username, password) = decoded.split_once(':').unwrap_or((&decoded, ""));
+            Some(
+                if password.is_empty() {
+                    username
+                } else {
+                    password
+                }
+                .to_string(),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Validate the authentication token against the room's tokens
 ///
-/// Returns the message with the authentication token removed
+/// Returns the message with the authentication token removed, and the token that matched
 pub fn validate_authentication(
     room_config: RoomConfig,
     headers: &HeaderMap,
     msg: &str,
-) -> anyhow::Result<String> {
-    if room_config.auth.is_some() {
-        // Check if the authentication token is in the headers
-        let token = {
-            // Allow both "authentication" and "auth"
-            if let Some(auth) = headers.get("authentication") {
-                auth.to_str().unwrap_or_default()
-            } else if let Some(auth) = headers.get("auth") {
-                auth.to_str().unwrap_or_default()
-            } else {
-                ""
-            }
-        };
-        if token == room_config.auth.clone().unwrap() {
-            return Ok(msg.to_string());
+) -> anyhow::Result<(String, Option<AuthToken>)> {
+    if room_config.unavailable {
+        return Err(anyhow::anyhow!(
+            "Failed to read the room's Authentication tokens"
+        ));
+    }
+    if room_config.tokens.is_empty() {
+        return Ok((msg.to_string(), None));
+    }
+    // Check if the authentication token is in the headers
+    let token = header_token(headers).unwrap_or_default();
+    let find = |token: &str| room_config.tokens.iter().find(|t| t.matches(token));
+    let (msg, matched) = match find(&token) {
+        Some(matched) => (msg.to_string(), matched),
+        None => {
+            // Allow the authentication token to be the first word in the message
+            let msg = msg.trim_start();
+            let (first, rest) = msg.split_once(char::is_whitespace).unwrap_or((msg, ""));
+            let Some(matched) = find(first) else {
+                return Err(anyhow::anyhow!("Incorrect Authentication Token"));
+            };
+            // Remove the token and any leading whitespace
+            (rest.trim_start().to_string(), matched)
         }
+    };
+    if matched.is_expired() {
+        return Err(anyhow::anyhow!("Authentication Token expired"));
+    }
+    Ok((msg, Some(matched.clone())))
+}
 
-        // Allow the authentication token to be the first word in the message
+/// Parse a duration like "30s", "10m", "2h" or "1d".
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(split);
+    let value: u64 = value.parse().ok()?;
+    let seconds = match unit.trim() {
+        "s" | "sec" | "secs" | "second" | "seconds" => 1,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60,
+        "h" | "hr" | "hrs" | "hour" | "hours" => 60 * 60,
+        "d" | "day" | "days" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(value.checked_mul(seconds)?))
+}
 
-        // Check if the message starts with the password
-        if !msg.starts_with(&room_config.auth.clone().unwrap()) {
-            return Err(anyhow::anyhow!("Incorrect Authentication Token"));
-        }
-        // Remove the password and any leading whitespace
-        Ok(msg
-            .trim_start_matches(&room_config.auth.unwrap())
-            .trim_start()
-            .to_string())
+/// Parse a time of day like "10am", "3:30pm" or "17:30"
+pub fn parse_time_of_day(time: &str) -> Option<NaiveTime> {
+    let time = time.trim();
+    let (time, pm) = match (time.strip_suffix("am"), time.strip_suffix("pm")) {
+        (Some(time), _) => (time.trim(), Some(false)),
+        (_, Some(time)) => (time.trim(), Some(true)),
+        // Without am/pm, require the minutes so it isn't mistaken for a timestamp
+        _ if time.contains(':') => (time, None),
+        _ => return None,
+    };
+    let (hour, minute) = match time.split_once(':') {
+        Some((hour, minute)) => (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?),
+        None => (time.parse::<u32>().ok()?, 0),
+    };
+    let hour = match pm {
+        Some(pm) if (1..=12).contains(&hour) => hour % 12 + if pm { 12 } else { 0 },
+        Some(_) => return None,
+        None => hour,
+    };
+    NaiveTime::from_hms_opt(hour, minute, 0)
+}
+
+/// Parse an ntfy style delay.
+///
+/// It can be a duration like "30m" or "in 2h", a unix timestamp, or a local time like "10am",
+/// "17:30" or "tomorrow, 3pm". A time of day without "tomorrow" is its next occurrence.
+///
+/// Returns how long to wait before sending.
+pub fn parse_delay(delay: &str) -> anyhow::Result<Duration> {
+    let lower = delay.trim().to_lowercase();
+    if let Some(duration) = parse_duration(lower.strip_prefix("in ").unwrap_or(&lower)) {
+        return Ok(duration);
+    }
+    if let Ok(timestamp) = lower.parse::<u64>() {
+        let Some(at) = UNIX_EPOCH.checked_add(Duration::from_secs(timestamp)) else {
+            return Err(anyhow::anyhow!("Invalid delay: {}", delay));
+        };
+        return Ok(at.duration_since(SystemTime::now()).unwrap_or_default());
+    }
+
+    let now = Local::now();
+    let (days, time) = match lower.strip_prefix("tomorrow") {
+        Some(time) => (1, time.trim_start_matches(',').trim()),
+        None => (0, lower.as_str()),
+    };
+    let time = if time.is_empty() && days > 0 {
+        Some(now.time())
     } else {
-        Ok(msg.to_string())
+        parse_time_of_day(time)
+    };
+    let at = time.and_then(|time| {
+        let date = now.date_naive() + Days::new(days);
+        let at = date.and_time(time).and_local_timezone(Local).earliest()?;
+        if at <= now {
+            (date + Days::new(1))
+                .and_time(time)
+                .and_local_timezone(Local)
+                .earliest()
+        } else {
+            Some(at)
+        }
+    });
+    match at {
+        Some(at) => Ok((at - now).to_std().unwrap_or_default()),
+        None => Err(anyhow::anyhow!("Invalid delay: {}", delay)),
     }
 }
 
@@ -421,3 +856,195 @@ pub async fn connect(config: MatrixConfig) -> anyhow::Result<Bot> {
 
     Ok(bot)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    fn basic(credentials: &str) -> HeaderMap {
+        headers(
+            "authorization",
+            &format!("Basic {}", STANDARD.encode(credentials)),
+        )
+    }
+
+    fn room_config(tokens: Vec<AuthToken>) -> RoomConfig {
+        RoomConfig {
+            tokens,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn header_token_parsing() {
+        let token = |headers: &HeaderMap| header_token(headers);
+        assert_eq!(token(&HeaderMap::new()), None);
+        assert_eq!(
+            token(&headers("authentication", "tok")).as_deref(),
+            Some("tok")
+        );
+        assert_eq!(token(&headers("auth", "tok")).as_deref(), Some("tok"));
+        assert_eq!(
+            token(&headers("authorization", "Bearer tok")).as_deref(),
+            Some("tok")
+        );
+        assert_eq!(
+            token(&headers("authorization", " bearer  tok ")).as_deref(),
+            Some("tok")
+        );
+        assert_eq!(token(&basic("user:tok")).as_deref(), Some("tok"));
+        // The password can contain colons
+        assert_eq!(token(&basic("user:tok:en")).as_deref(), Some("tok:en"));
+        // Without a password the username is the token
+        assert_eq!(token(&basic("tok:")).as_deref(), Some("tok"));
+        assert_eq!(token(&basic("tok")).as_deref(), Some("tok"));
+        assert_eq!(token(&headers("authorization", "Basic not-base64!")), None);
+        assert_eq!(token(&headers("authorization", "Digest tok")), None);
+        assert_eq!(token(&headers("authorization", "tok")), None);
+    }
+
+    #[test]
+    fn hashed_token() {
+        let config = room_config(vec![AuthToken::new("ci", "secret-token")]);
+        let (msg, token) = validate_authentication(
+            config.clone(),
+            &headers("authorization", "Bearer secret-token"),
+            "hello",
+        )
+        .unwrap();
+        assert_eq!(msg, "hello");
+        assert_eq!(token.unwrap().name, "ci");
+
+        // The token can be the first word of the message, and is removed from it
+        let (msg, token) =
+            validate_authentication(config.clone(), &HeaderMap::new(), "  secret-token  hello")
+                .unwrap();
+        assert_eq!(msg, "hello");
+        assert_eq!(token.unwrap().name, "ci");
+
+        assert!(validate_authentication(
+            config.clone(),
+            &headers("authorization", "Bearer wrong"),
+            "hello"
+        )
+        .is_err());
+        assert!(validate_authentication(config.clone(), &HeaderMap::new(), "hello").is_err());
+        assert!(validate_authentication(config, &HeaderMap::new(), "").is_err());
+    }
+
+    #[test]
+    fn legacy_token() {
+        // A token migrated from the old tag, saved before tokens had a scope
+        let mut saved = serde_json::to_value(AuthToken::new(DEFAULT_TOKEN_NAME, "old")).unwrap();
+        saved.as_object_mut().unwrap().remove("scope");
+        let token: AuthToken = serde_json::from_value(saved).unwrap();
+        assert!(!token.scope.is_restricted());
+        let config = room_config(vec![AuthToken::new("ci", "new"), token]);
+
+        let (msg, token) =
+            validate_authentication(config.clone(), &headers("auth", "old"), "hello").unwrap();
+        assert_eq!(msg, "hello");
+        assert_eq!(token.unwrap().name, DEFAULT_TOKEN_NAME);
+        let (msg, token) =
+            validate_authentication(config.clone(), &HeaderMap::new(), "old hello").unwrap();
+        assert_eq!(msg, "hello");
+        assert_eq!(token.unwrap().name, DEFAULT_TOKEN_NAME);
+        assert!(validate_authentication(config, &headers("auth", "older"), "hello").is_err());
+    }
+
+    #[test]
+    fn expired_token() {
+        let mut token = AuthToken::new("ci", "tok");
+        token.scope.expires = Some(Local::now().timestamp() - 1);
+        let config = room_config(vec![token]);
+        let err = validate_authentication(config, &headers("auth", "tok"), "hello").unwrap_err();
+        assert!(err.to_string().contains("expired"));
+    }
+
+    #[test]
+    fn without_tokens() {
+        let (msg, token) =
+            validate_authentication(RoomConfig::default(), &HeaderMap::new(), "hello").unwrap();
+        assert_eq!(msg, "hello");
+        assert!(token.is_none());
+
+        // Fail closed if the tokens couldn't be read
+        let config = RoomConfig {
+            unavailable: true,
+            ..Default::default()
+        };
+        assert!(validate_authentication(config, &headers("auth", "tok"), "hello").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration(" 10 min "), Some(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(
+            parse_duration("1day"),
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("0s"), Some(Duration::ZERO));
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("-5m"), None);
+        assert_eq!(parse_duration("5 weeks"), None);
+        // Too big for a u64, and overflowing once it is multiplied into seconds
+        assert_eq!(parse_duration("99999999999999999999d"), None);
+        assert_eq!(parse_duration("18446744073709551615d"), None);
+        assert_eq!(
+            parse_duration("18446744073709551615s"),
+            Some(Duration::from_secs(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn delays() {
+        use crate::outbox::MAX_DELAY;
+
+        assert_eq!(parse_delay("30m").unwrap(), Duration::from_secs(30 * 60));
+        assert_eq!(
+            parse_delay("In 2h").unwrap(),
+            Duration::from_secs(2 * 60 * 60)
+        );
+        // The limit is inclusive
+        assert_eq!(parse_delay("3d").unwrap(), MAX_DELAY);
+        assert!(parse_delay("72h").unwrap() <= MAX_DELAY);
+        assert!(parse_delay("73h").unwrap() > MAX_DELAY);
+        assert!(parse_delay("18446744073709551615s").unwrap() > MAX_DELAY);
+        assert!(parse_delay("99999999999999999999d").is_err());
+
+        // Unix timestamps, in the past they are sent straight away
+        assert_eq!(parse_delay("1700000000").unwrap(), Duration::ZERO);
+        let soon = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 600;
+        let delay = parse_delay(&soon.to_string()).unwrap();
+        assert!(delay > Duration::from_secs(590) && delay <= Duration::from_secs(600));
+        assert!(parse_delay("18446744073709551615").is_err());
+
+        // Times of day are in the next 24 hours
+        let day = Duration::from_secs(24 * 60 * 60);
+        for time in ["10am", "3:30pm", "17:30", "12am"] {
+            let delay = parse_delay(time).unwrap();
+            assert!(delay <= day, "{}", time);
+        }
+        let tomorrow = parse_delay("tomorrow, 3pm").unwrap();
+        assert!(tomorrow > Duration::ZERO && tomorrow <= 2 * day);
+        assert!(parse_delay("tomorrow").unwrap() <= day);
+
+        assert!(parse_delay("").is_err());
+        assert!(parse_delay("soon").is_err());
+        assert!(parse_delay("13pm").is_err());
+        assert!(parse_delay("25:00").is_err());
+    }
+}
@@ -0,0 +1,60 @@
+/// Prometheus Alertmanager webhooks
+use serde::Deserialize;
+
+use std::collections::HashMap;
+
+use super::{AlertNotification, AlertSummary, WebhookPoke};
+
+/// An alert in the notification, https://prometheus.io/docs/alerting/latest/configuration/#webhook_config
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Alert {
+    status: String,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+    #[serde(rename = "generatorURL")]
+    generator_url: Option<String>,
+}
+
+impl AlertSummary for Alert {
+    fn status(&self) -> &str {
+        &self.status
+    }
+
+    fn labels(&self) -> &HashMap<String, String> {
+        &self.labels
+    }
+
+    fn summary(&self) -> String {
+        let mut summary = format!(
+            "**{}**",
+            self.labels
+                .get("alertname")
+                .map(String::as_str)
+                .unwrap_or("Alert")
+        );
+        if let Some(target) = self
+            .labels
+            .get("instance")
+            .or_else(|| self.labels.get("job"))
+        {
+            summary.push_str(&format!(" on `{}`", target));
+        }
+        if let Some(description) = ["summary", "description", "message"]
+            .iter()
+            .find_map(|key| self.annotations.get(*key))
+        {
+            summary.push_str(&format!(": {}", description));
+        }
+        if let Some(url) = &self.generator_url {
+            summary.push_str(&format!(" ([Source]({}))", url));
+        }
+        summary
+    }
+}
+
+pub fn parse(body: &[u8]) -> anyhow::Result<WebhookPoke> {
+    Ok(serde_json::from_slice::<AlertNotification<Alert>>(body)?.poke())
+}
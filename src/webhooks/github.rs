@@ -0,0 +1,270 @@
+/// GitHub, Gitea and Forgejo webhooks
+use serde::Deserialize;
+
+use super::{link, WebhookPoke, MAX_COMMITS};
+
+/// The fields used from every event.
+///
+/// https://docs.github.com/en/webhooks/webhook-events-and-payloads
+#[derive(Debug, Deserialize)]
+struct Event {
+    action: Option<String>,
+    repository: Option<Repository>,
+    sender: Option<User>,
+    // push
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    /// Gitea calls it compare_url
+    #[serde(alias = "compare_url")]
+    compare: Option<String>,
+    #[serde(default)]
+    commits: Vec<Commit>,
+    #[serde(default)]
+    created: bool,
+    #[serde(default)]
+    deleted: bool,
+    #[serde(default)]
+    forced: bool,
+    // pull_request and issues
+    pull_request: Option<Issue>,
+    issue: Option<Issue>,
+    // release
+    release: Option<Release>,
+    // workflow_run
+    workflow_run: Option<WorkflowRun>,
+    // ping
+    zen: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Repository {
+    full_name: String,
+    html_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    login: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Commit {
+    id: String,
+    message: String,
+    url: Option<String>,
+    author: Option<CommitAuthor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitAuthor {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Issue {
+    number: u64,
+    title: String,
+    html_url: Option<String>,
+    user: Option<User>,
+    #[serde(default)]
+    merged: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    name: Option<String>,
+    html_url: Option<String>,
+    #[serde(default)]
+    prerelease: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkflowRun {
+    name: Option<String>,
+    head_branch: Option<String>,
+    conclusion: Option<String>,
+    html_url: Option<String>,
+}
+
+pub fn parse(event_type: &str, body: &[u8]) -> anyhow::Result<Option<WebhookPoke>> {
+    let event: Event = serde_json::from_slice(body)?;
+    let repo = event
+        .repository
+        .as_ref()
+        .map(|repo| repo.full_name.as_str())
+        .unwrap_or("unknown");
+    let repo_url = event
+        .repository
+        .as_ref()
+        .and_then(|repo| repo.html_url.as_deref());
+    let sender = event
+        .sender
+        .as_ref()
+        .map(|sender| sender.login.as_str())
+        .unwrap_or("someone");
+    let action = event.action.as_deref().unwrap_or_default();
+
+    let poke = match event_type {
+        "ping" => WebhookPoke {
+            title: Some(format!("[{}] Webhook added", repo)),
+            message: event.zen.unwrap_or_default(),
+            click: repo_url.map(String::from),
+            ..Default::default()
+        },
+        "push" => {
+            let git_ref = event.git_ref.as_deref().unwrap_or_default();
+            let (kind, name) = match git_ref.strip_prefix("refs/tags/") {
+                Some(tag) => ("tag", tag),
+                None => ("branch", git_ref.trim_start_matches("refs/heads/")),
+            };
+            let title = if event.deleted {
+                format!("[{}] {} deleted {} {}", repo, sender, kind, name)
+            } else if kind == "tag" {
+                format!("[{}] {} pushed tag {}", repo, sender, name)
+            } else if event.commits.is_empty() && event.created {
+                format!("[{}] {} created branch {}", repo, sender, name)
+            } else {
+                format!(
+                    "[{}] {} {} {} commit{} to {}",
+                    repo,
+                    sender,
+                    if event.forced {
+                        "force-pushed"
+                    } else {
+                        "pushed"
+                    },
+                    event.commits.len(),
+                    if event.commits.len() == 1 { "" } else { "s" },
+                    name
+                )
+            };
+            let mut lines: Vec<String> = event
+                .commits
+                .iter()
+                .take(MAX_COMMITS)
+                .map(|commit| {
+                    let short_id = commit.id.get(..7).unwrap_or(&commit.id);
+                    let summary = commit.message.lines().next().unwrap_or_default();
+                    let author = commit
+                        .author
+                        .as_ref()
+                        .map(|author| format!(" - {}", author.name))
+                        .unwrap_or_default();
+                    format!(
+                        "- {} {}{}",
+                        link(&format!("`{}`", short_id), commit.url.as_deref()),
+                        summary,
+                        author
+                    )
+                })
+                .collect();
+            if event.commits.len() > MAX_COMMITS {
+                lines.push(format!("- And {} more", event.commits.len() - MAX_COMMITS));
+            }
+            WebhookPoke {
+                title: Some(title),
+                message: lines.join("\n"),
+                click: event.compare,
+                ..Default::default()
+            }
+        }
+        "pull_request" | "issues" => {
+            let (noun, issue) = if event_type == "pull_request" {
+                ("Pull request", event.pull_request)
+            } else {
+                ("Issue", event.issue)
+            };
+            let Some(issue) = issue else {
+                return Err(anyhow::anyhow!("Missing {} in {} event", noun, event_type));
+            };
+            // Only the changes in state are worth a poke
+            let (verb, tag) = match action {
+                "opened" => ("opened", "new"),
+                "reopened" => ("reopened", "repeat"),
+                "ready_for_review" => ("is ready for review", "eyes"),
+                "closed" if issue.merged => ("merged", "tada"),
+                "closed" => ("closed", "no_entry_sign"),
+                _ => return Ok(None),
+            };
+            let author = issue
+                .user
+                .as_ref()
+                .map(|user| format!(" by {}", user.login))
+                .unwrap_or_default();
+            WebhookPoke {
+                title: Some(format!("[{}] {} #{} {}", repo, noun, issue.number, verb)),
+                message: format!(
+                    "{}{}",
+                    link(&issue.title, issue.html_url.as_deref()),
+                    author
+                ),
+                tags: Some(vec![tag.to_string()]),
+                // Keep the updates to the same pull request or issue together
+                group: Some(format!("{}#{}", repo, issue.number)),
+                ..Default::default()
+            }
+        }
+        "release" => {
+            // GitHub sends several actions for each release
+            if action != "published" {
+                return Ok(None);
+            }
+            let Some(release) = event.release else {
+                return Err(anyhow::anyhow!("Missing release in release event"));
+            };
+            let name = release
+                .name
+                .filter(|name| !name.is_empty())
+                .unwrap_or(release.tag_name);
+            WebhookPoke {
+                title: Some(format!(
+                    "[{}] {}released {}",
+                    repo,
+                    if release.prerelease { "Pre-" } else { "" },
+                    name
+                )),
+                message: link("Release notes", release.html_url.as_deref()),
+                tags: Some(vec!["package".to_string()]),
+                ..Default::default()
+            }
+        }
+        "workflow_run" => {
+            // Only completed runs are worth a poke
+            if action != "completed" {
+                return Ok(None);
+            }
+            let Some(run) = event.workflow_run else {
+                return Err(anyhow::anyhow!(
+                    "Missing workflow_run in workflow_run event"
+                ));
+            };
+            let conclusion = run.conclusion.as_deref().unwrap_or("completed");
+            let (priority, tag) = match conclusion {
+                "success" => (None, "white_check_mark"),
+                "failure" | "timed_out" | "startup_failure" => (Some(4), "x"),
+                _ => (None, "grey_question"),
+            };
+            let branch = run
+                .head_branch
+                .as_ref()
+                .map(|branch| format!(" on {}", branch))
+                .unwrap_or_default();
+            WebhookPoke {
+                title: Some(format!(
+                    "[{}] {} {}{}",
+                    repo,
+                    run.name.as_deref().unwrap_or("Workflow"),
+                    conclusion.replace('_', " "),
+                    branch
+                )),
+                message: link("View run", run.html_url.as_deref()),
+                priority,
+                tags: Some(vec![tag.to_string()]),
+                ..Default::default()
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(poke))
+}
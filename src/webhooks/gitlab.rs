@@ -0,0 +1,325 @@
+/// GitLab webhooks
+use serde::Deserialize;
+
+use super::{link, WebhookPoke, MAX_COMMITS};
+
+/// The fields used from every event.
+///
+/// https://docs.gitlab.com/ee/user/project/integrations/webhook_events.html
+#[derive(Debug, Deserialize)]
+struct Event {
+    project: Option<Project>,
+    user: Option<User>,
+    object_attributes: Option<ObjectAttributes>,
+    // push
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    user_name: Option<String>,
+    before: Option<String>,
+    after: Option<String>,
+    #[serde(default)]
+    commits: Vec<Commit>,
+    total_commits_count: Option<usize>,
+    // job
+    build_id: Option<u64>,
+    build_name: Option<String>,
+    build_stage: Option<String>,
+    build_status: Option<String>,
+    build_failure_reason: Option<String>,
+    #[serde(default)]
+    build_allow_failure: bool,
+    pipeline_id: Option<u64>,
+    project_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Project {
+    path_with_namespace: String,
+    web_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    username: Option<String>,
+    name: Option<String>,
+}
+
+/// Attributes of the merge request or pipeline
+#[derive(Debug, Deserialize)]
+struct ObjectAttributes {
+    id: Option<u64>,
+    iid: Option<u64>,
+    title: Option<String>,
+    url: Option<String>,
+    action: Option<String>,
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    status: Option<String>,
+    duration: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Commit {
+    id: String,
+    message: String,
+    url: Option<String>,
+    author: Option<CommitAuthor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitAuthor {
+    name: String,
+}
+
+/// Check for the all zeros commit GitLab sends for a ref that doesn't exist
+fn is_zero_sha(sha: Option<&str>) -> bool {
+    sha.is_some_and(|sha| !sha.is_empty() && sha.chars().all(|c| c == '0'))
+}
+
+pub fn parse(event_type: &str, body: &[u8]) -> anyhow::Result<Option<WebhookPoke>> {
+    let event: Event = serde_json::from_slice(body)?;
+    let project = event
+        .project
+        .as_ref()
+        .map(|project| project.path_with_namespace.as_str())
+        .or(event.project_name.as_deref())
+        .unwrap_or("unknown");
+    let project_url = event
+        .project
+        .as_ref()
+        .and_then(|project| project.web_url.as_deref());
+    let user = event
+        .user
+        .as_ref()
+        .and_then(|user| user.username.as_deref().or(user.name.as_deref()))
+        .or(event.user_name.as_deref())
+        .unwrap_or("someone");
+
+    let poke = match event_type {
+        "Push Hook" | "Tag Push Hook" => {
+            let git_ref = event.git_ref.as_deref().unwrap_or_default();
+            let (kind, name) = match git_ref.strip_prefix("refs/tags/") {
+                Some(tag) => ("tag", tag),
+                None => ("branch", git_ref.trim_start_matches("refs/heads/")),
+            };
+            let total = event.total_commits_count.unwrap_or(event.commits.len());
+            // The old commit is all zeros when the ref is created, and the new one when deleted
+            let created = is_zero_sha(event.before.as_deref());
+            let deleted = is_zero_sha(event.after.as_deref());
+            let title = if deleted {
+                format!("[{}] {} deleted {} {}", project, user, kind, name)
+            } else if kind == "tag" {
+                format!("[{}] {} pushed tag {}", project, user, name)
+            } else if created && total == 0 {
+                format!("[{}] {} created branch {}", project, user, name)
+            } else {
+                format!(
+                    "[{}] {} pushed {} commit{} to {}",
+                    project,
+                    user,
+                    total,
+                    if total == 1 { "" } else { "s" },
+                    name
+                )
+            };
+            let mut lines: Vec<String> = event
+                .commits
+                .iter()
+                .take(MAX_COMMITS)
+                .map(|commit| {
+                    let short_id = commit.id.get(..8).unwrap_or(&commit.id);
+                    let summary = commit.message.lines().next().unwrap_or_default();
+                    let author = commit
+                        .author
+                        .as_ref()
+                        .map(|author| format!(" - {}", author.name))
+                        .unwrap_or_default();
+                    format!(
+                        "- {} {}{}",
+                        link(&format!("`{}`", short_id), commit.url.as_deref()),
+                        summary,
+                        author
+                    )
+                })
+                .collect();
+            if total > lines.len() && !lines.is_empty() {
+                lines.push(format!("- And {} more", total - lines.len()));
+            }
+            WebhookPoke {
+                title: Some(title),
+                message: lines.join("\n"),
+                click: project_url.map(String::from),
+                ..Default::default()
+            }
+        }
+        "Merge Request Hook" => {
+            let Some(mr) = event.object_attributes else {
+                return Err(anyhow::anyhow!(
+                    "Missing object_attributes in {}",
+                    event_type
+                ));
+            };
+            // Only the changes in state are worth a poke
+            let (verb, tag) = match mr.action.as_deref().unwrap_or_default() {
+                "open" => ("opened", "new"),
+                "reopen" => ("reopened", "repeat"),
+                "approved" => ("approved", "+1"),
+                "merge" => ("merged", "tada"),
+                "close" => ("closed", "no_entry_sign"),
+                _ => return Ok(None),
+            };
+            let iid = mr.iid.unwrap_or_default();
+            WebhookPoke {
+                title: Some(format!(
+                    "[{}] Merge request !{} {} by {}",
+                    project, iid, verb, user
+                )),
+                message: link(mr.title.as_deref().unwrap_or_default(), mr.url.as_deref()),
+                tags: Some(vec![tag.to_string()]),
+                // Keep the updates to the same merge request together
+                group: Some(format!("{}!{}", project, iid)),
+                ..Default::default()
+            }
+        }
+        "Pipeline Hook" => {
+            let Some(pipeline) = event.object_attributes else {
+                return Err(anyhow::anyhow!(
+                    "Missing object_attributes in {}",
+                    event_type
+                ));
+            };
+            // Only finished pipelines are worth a poke
+            let status = pipeline.status.as_deref().unwrap_or_default();
+            let (priority, tag) = match status {
+                "success" => (None, "white_check_mark"),
+                "failed" => (Some(4), "x"),
+                "canceled" => (None, "no_entry_sign"),
+                _ => return Ok(None),
+            };
+            let id = pipeline.id.unwrap_or_default();
+            let branch = pipeline
+                .git_ref
+                .as_ref()
+                .map(|git_ref| format!(" on {}", git_ref))
+                .unwrap_or_default();
+            let duration = pipeline
+                .duration
+                .map(|duration| format!(" after {}m {}s", duration / 60, duration % 60))
+                .unwrap_or_default();
+            let url = pipeline
+                .url
+                .or(project_url.map(|url| format!("{}/-/pipelines/{}", url, id)));
+            WebhookPoke {
+                title: Some(format!(
+                    "[{}] Pipeline #{} {}{}",
+                    project, id, status, branch
+                )),
+                message: format!("{}{}", link("View pipeline", url.as_deref()), duration),
+                priority,
+                tags: Some(vec![tag.to_string()]),
+                // Failed jobs are threaded with their pipeline
+                group: Some(format!("{} pipeline {}", project, id)),
+                ..Default::default()
+            }
+        }
+        "Job Hook" => {
+            // Only failed jobs are worth a poke, the pipeline reports the rest
+            if event.build_status.as_deref() != Some("failed") {
+                return Ok(None);
+            }
+            let name = event.build_name.as_deref().unwrap_or("Job");
+            let stage = event
+                .build_stage
+                .as_ref()
+                .map(|stage| format!(" in stage {}", stage))
+                .unwrap_or_default();
+            let reason = event
+                .build_failure_reason
+                .as_ref()
+                .map(|reason| format!(" ({})", reason.replace('_', " ")))
+                .unwrap_or_default();
+            let url = project_url
+                .zip(event.build_id)
+                .map(|(url, id)| format!("{}/-/jobs/{}", url, id));
+            WebhookPoke {
+                title: Some(format!("[{}] Job {} failed{}", project, name, stage)),
+                message: format!("{}{}", link("View job", url.as_deref()), reason),
+                // Jobs that are allowed to fail don't fail the pipeline
+                priority: if event.build_allow_failure {
+                    None
+                } else {
+                    Some(4)
+                },
+                tags: Some(vec!["x".to_string()]),
+                group: event
+                    .pipeline_id
+                    .map(|id| format!("{} pipeline {}", project, id)),
+                ..Default::default()
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(poke))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZERO: &str = "0000000000000000000000000000000000000000";
+    const SHA: &str = "da1560886d4f094c3e6c9ef40349f7d38b5d27d7";
+
+    fn push(before: &str, after: &str, commits: usize) -> String {
+        let commits: Vec<serde_json::Value> = (0..commits)
+            .map(|i| serde_json::json!({ "id": format!("{}{}", i, SHA), "message": "Fix it\n\nbody" }))
+            .collect();
+        let body = serde_json::json!({
+            "ref": "refs/heads/feature",
+            "user_username": "jsmith",
+            "user_name": "John Smith",
+            "before": before,
+            "after": after,
+            "project": { "path_with_namespace": "group/project" },
+            "commits": commits,
+            "total_commits_count": commits.len(),
+        });
+        parse("Push Hook", body.to_string().as_bytes())
+            .unwrap()
+            .unwrap()
+            .title
+            .unwrap()
+    }
+
+    #[test]
+    fn push_commits() {
+        assert_eq!(
+            push(SHA, SHA, 2),
+            "[group/project] John Smith pushed 2 commits to feature"
+        );
+        assert_eq!(
+            push(SHA, SHA, 1),
+            "[group/project] John Smith pushed 1 commit to feature"
+        );
+    }
+
+    #[test]
+    fn branch_created() {
+        assert_eq!(
+            push(ZERO, SHA, 0),
+            "[group/project] John Smith created branch feature"
+        );
+        // New commits pushed along with the branch are still listed
+        assert_eq!(
+            push(ZERO, SHA, 3),
+            "[group/project] John Smith pushed 3 commits to feature"
+        );
+    }
+
+    #[test]
+    fn branch_deleted() {
+        assert_eq!(
+            push(SHA, ZERO, 0),
+            "[group/project] John Smith deleted branch feature"
+        );
+    }
+}
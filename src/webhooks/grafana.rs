@@ -0,0 +1,80 @@
+/// Grafana unified alerting webhooks
+use serde::Deserialize;
+
+use std::collections::HashMap;
+
+use super::{AlertNotification, AlertSummary, WebhookPoke};
+
+/// An alert in the notification, https://grafana.com/docs/grafana/latest/alerting/configure-notifications/manage-contact-points/integrations/webhook-notifier/
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Alert {
+    status: String,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+    /// The query results, e.g. "B": 95.2
+    #[serde(default)]
+    values: Option<HashMap<String, serde_json::Value>>,
+    #[serde(rename = "dashboardURL")]
+    dashboard_url: Option<String>,
+    #[serde(rename = "panelURL")]
+    panel_url: Option<String>,
+    #[serde(rename = "generatorURL")]
+    generator_url: Option<String>,
+}
+
+impl AlertSummary for Alert {
+    fn status(&self) -> &str {
+        &self.status
+    }
+
+    fn labels(&self) -> &HashMap<String, String> {
+        &self.labels
+    }
+
+    fn summary(&self) -> String {
+        let mut summary = format!(
+            "**{}**",
+            self.labels
+                .get("alertname")
+                .map(String::as_str)
+                .unwrap_or("Alert")
+        );
+        if let Some(description) = ["summary", "description", "message"]
+            .iter()
+            .find_map(|key| self.annotations.get(*key))
+        {
+            summary.push_str(&format!(": {}", description));
+        }
+        if let Some(values) = self.values.as_ref().filter(|values| !values.is_empty()) {
+            let mut values: Vec<String> = values
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            values.sort();
+            summary.push_str(&format!(" ({})", values.join(", ")));
+        }
+        let links: Vec<String> = [
+            ("Dashboard", &self.dashboard_url),
+            ("Panel", &self.panel_url),
+            ("Source", &self.generator_url),
+        ]
+        .iter()
+        .filter_map(|(name, url)| {
+            url.as_ref()
+                .filter(|url| !url.is_empty())
+                .map(|url| format!("[{}]({})", name, url))
+        })
+        .collect();
+        if !links.is_empty() {
+            summary.push_str(&format!(" {}", links.join(" | ")));
+        }
+        summary
+    }
+}
+
+pub fn parse(body: &[u8]) -> anyhow::Result<WebhookPoke> {
+    Ok(serde_json::from_slice::<AlertNotification<Alert>>(body)?.poke())
+}
@@ -0,0 +1,241 @@
+/// Receivers for the webhooks sent by other services
+mod alertmanager;
+mod github;
+mod gitlab;
+mod grafana;
+mod template;
+mod uptime_kuma;
+
+use hmac::{Hmac, Mac};
+use hyper::header::HeaderValue;
+use hyper::HeaderMap;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use std::collections::HashMap;
+
+pub use template::Template;
+
+/// A service that sends webhooks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Webhook {
+    Alertmanager,
+    GitHub,
+    Gitea,
+    Forgejo,
+    GitLab,
+    Grafana,
+    UptimeKuma,
+}
+
+/// A poke built from a webhook
+#[derive(Debug, Default)]
+pub struct WebhookPoke {
+    pub title: Option<String>,
+    pub message: String,
+    pub priority: Option<u8>,
+    pub tags: Option<Vec<String>>,
+    /// URL to link to
+    pub click: Option<String>,
+    /// Correlation key to thread related pokes
+    pub group: Option<String>,
+}
+
+impl Webhook {
+    /// Split a webhook path like "room/alertmanager" into the room and webhook
+    pub fn from_path(path: &str) -> Option<(&str, Self)> {
+        let (room, webhook) = path.rsplit_once('/')?;
+        let webhook = match webhook {
+            "alertmanager" => Webhook::Alertmanager,
+            "github" => Webhook::GitHub,
+            "gitea" => Webhook::Gitea,
+            "forgejo" => Webhook::Forgejo,
+            "gitlab" => Webhook::GitLab,
+            "grafana" => Webhook::Grafana,
+            "uptime-kuma" => Webhook::UptimeKuma,
+            _ => return None,
+        };
+        Some((room, webhook))
+    }
+
+    /// Build the poke from the webhook request.
+    ///
+    /// Returns None for events that aren't worth a poke.
+    pub fn parse(&self, headers: &HeaderMap, body: &[u8]) -> anyhow::Result<Option<WebhookPoke>> {
+        match self {
+            Webhook::Alertmanager => alertmanager::parse(body).map(Some),
+            Webhook::Grafana => grafana::parse(body).map(Some),
+            Webhook::UptimeKuma => uptime_kuma::parse(body).map(Some),
+            // Gitea and Forgejo also send the GitHub event header
+            Webhook::GitHub | Webhook::Gitea | Webhook::Forgejo => {
+                let event = ["x-github-event", "x-gitea-event", "x-forgejo-event"]
+                    .iter()
+                    .find_map(|name| header_str(headers, name))
+                    .ok_or(anyhow::anyhow!("Missing event header"))?;
+                github::parse(event, body)
+            }
+            Webhook::GitLab => {
+                let event = header_str(headers, "x-gitlab-event")
+                    .ok_or(anyhow::anyhow!("Missing X-Gitlab-Event header"))?;
+                gitlab::parse(event, body)
+            }
+        }
+    }
+
+    /// The authentication token sent by the webhook, in place of the Authentication header
+    pub fn auth_token(&self, headers: &HeaderMap) -> Option<HeaderValue> {
+        match self {
+            Webhook::GitLab => headers.get("x-gitlab-token").cloned(),
+            _ => None,
+        }
+    }
+
+    /// Check the signature of the request body against the secret.
+    ///
+    /// Returns None if this webhook isn't signed.
+    pub fn verify_signature(&self, headers: &HeaderMap, body: &[u8], secret: &str) -> Option<bool> {
+        let signature_headers: &[&str] = match self {
+            Webhook::Alertmanager | Webhook::GitLab | Webhook::Grafana | Webhook::UptimeKuma => {
+                return None
+            }
+            Webhook::GitHub => &["x-hub-signature-256"],
+            Webhook::Gitea => &["x-gitea-signature", "x-hub-signature-256"],
+            Webhook::Forgejo => &[
+                "x-forgejo-signature",
+                "x-gitea-signature",
+                "x-hub-signature-256",
+            ],
+        };
+        let Some(signature) = signature_headers
+            .iter()
+            .find_map(|name| header_str(headers, name))
+        else {
+            return Some(false);
+        };
+        // GitHub prefixes the hex digest with the algorithm
+        let Ok(signature) = hex::decode(signature.trim_start_matches("sha256=")) else {
+            return Some(false);
+        };
+        let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+            return Some(false);
+        };
+        mac.update(body);
+        Some(mac.verify_slice(&signature).is_ok())
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Max number of commits listed for a push
+const MAX_COMMITS: usize = 10;
+
+/// Make a markdown link, or just the text if there is no URL
+fn link(text: &str, url: Option<&str>) -> String {
+    match url {
+        Some(url) => format!("[{}]({})", text, url),
+        None => text.to_string(),
+    }
+}
+
+/// Convert a severity label, like "critical" or "warning", to a priority
+pub fn severity_priority(severity: &str) -> u8 {
+    match &severity.to_lowercase()[..] {
+        "critical" | "crit" | "emergency" | "page" | "disaster" => 5,
+        "error" | "err" | "major" => 4,
+        "warning" | "warn" | "average" => 3,
+        "info" | "information" | "minor" | "none" => 2,
+        severity => crate::utils::parse_priority(severity),
+    }
+}
+
+/// An alert in an Alertmanager or Grafana notification
+trait AlertSummary {
+    fn status(&self) -> &str;
+    fn labels(&self) -> &HashMap<String, String>;
+    /// A single line describing the alert
+    fn summary(&self) -> String;
+}
+
+/// The notification sent by Alertmanager, Grafana sends the same fields and a title
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AlertNotification<A> {
+    status: String,
+    title: Option<String>,
+    #[serde(default = "Vec::new")]
+    alerts: Vec<A>,
+    group_key: Option<String>,
+    #[serde(default)]
+    truncated_alerts: usize,
+    #[serde(default)]
+    group_labels: HashMap<String, String>,
+    #[serde(default)]
+    common_labels: HashMap<String, String>,
+    #[serde(rename = "externalURL")]
+    external_url: Option<String>,
+}
+
+impl<A: AlertSummary> AlertNotification<A> {
+    /// List the firing and resolved alerts
+    fn poke(self) -> WebhookPoke {
+        let (firing, resolved): (Vec<&A>, Vec<&A>) = self
+            .alerts
+            .iter()
+            .partition(|alert| alert.status() == "firing");
+
+        let mut sections = Vec::new();
+        for (heading, alerts) in [("Firing", &firing), ("Resolved", &resolved)] {
+            if !alerts.is_empty() {
+                let lines: Vec<String> = alerts
+                    .iter()
+                    .map(|alert| format!("- {}", alert.summary()))
+                    .collect();
+                sections.push(format!("**{}**\n\n{}", heading, lines.join("\n")));
+            }
+        }
+        if self.truncated_alerts > 0 {
+            sections.push(format!("And {} more alerts", self.truncated_alerts));
+        }
+
+        let name = self
+            .group_labels
+            .get("alertname")
+            .or_else(|| self.common_labels.get("alertname"))
+            .map(String::as_str)
+            .unwrap_or("Alerts");
+        let is_firing = self.status == "firing";
+        // Grafana already builds a title like "[FIRING:1] HighCPU"
+        let title = self.title.unwrap_or(if is_firing {
+            format!("[FIRING:{}] {}", firing.len(), name)
+        } else {
+            format!("[RESOLVED] {}", name)
+        });
+
+        // Only firing alerts need attention, use the most severe one
+        let priority = if is_firing {
+            firing
+                .iter()
+                .filter_map(|alert| alert.labels().get("severity"))
+                .map(|severity| severity_priority(severity))
+                .max()
+        } else {
+            None
+        };
+
+        WebhookPoke {
+            title: Some(title),
+            message: sections.join("\n\n"),
+            priority,
+            tags: Some(vec![if is_firing {
+                "rotating_light".to_string()
+            } else {
+                "white_check_mark".to_string()
+            }]),
+            click: self.external_url,
+            // Resolved notifications are threaded under the firing notification
+            group: self.group_key,
+        }
+    }
+}
@@ -0,0 +1,56 @@
+/// Templates from the config to build pokes from any JSON webhook
+use minijinja::Environment;
+use serde::Deserialize;
+
+use super::{severity_priority, WebhookPoke};
+
+/// A template for the poke, rendered with the fields of the JSON body.
+///
+/// Uses Jinja syntax, e.g. "{{ event.title }} in {{ project }}".
+#[derive(Debug, Deserialize, Clone)]
+pub struct Template {
+    pub title: Option<String>,
+    pub message: String,
+    /// A number from 1 to 5, or a name like "high" or "critical"
+    pub priority: Option<String>,
+    /// Comma separated list of tags
+    pub tags: Option<String>,
+    /// URL to link to
+    pub click: Option<String>,
+    /// Correlation key to thread related pokes
+    pub group: Option<String>,
+}
+
+impl Template {
+    /// Render the poke from the JSON body
+    pub fn render(&self, body: &[u8]) -> anyhow::Result<WebhookPoke> {
+        let context: serde_json::Value = serde_json::from_slice(body)?;
+        if !context.is_object() {
+            return Err(anyhow::anyhow!("Templates need a JSON object"));
+        }
+        let env = Environment::new();
+        let render = |template: &Option<String>| -> anyhow::Result<Option<String>> {
+            let Some(template) = template else {
+                return Ok(None);
+            };
+            let rendered = env.render_str(template, &context)?;
+            let rendered = rendered.trim();
+            Ok((!rendered.is_empty()).then(|| rendered.to_string()))
+        };
+
+        Ok(WebhookPoke {
+            title: render(&self.title)?,
+            message: env.render_str(&self.message, &context)?,
+            priority: render(&self.priority)?.map(|priority| severity_priority(&priority)),
+            tags: render(&self.tags)?.map(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .map(String::from)
+                    .collect()
+            }),
+            click: render(&self.click)?,
+            group: render(&self.group)?,
+        })
+    }
+}
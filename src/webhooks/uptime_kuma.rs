@@ -0,0 +1,72 @@
+/// Uptime Kuma webhooks
+use serde::Deserialize;
+
+use super::WebhookPoke;
+
+/// The JSON body of Uptime Kuma's webhook notification.
+///
+/// The heartbeat and monitor are null for test notifications.
+#[derive(Debug, Deserialize)]
+struct Notification {
+    heartbeat: Option<Heartbeat>,
+    monitor: Option<Monitor>,
+    #[serde(default)]
+    msg: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Heartbeat {
+    /// 0 is down, 1 is up, 2 is pending and 3 is maintenance
+    status: u8,
+    #[serde(default)]
+    msg: String,
+    /// Response time in milliseconds
+    ping: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Monitor {
+    id: u64,
+    name: String,
+    url: Option<String>,
+    hostname: Option<String>,
+}
+
+pub fn parse(body: &[u8]) -> anyhow::Result<WebhookPoke> {
+    let notification: Notification = serde_json::from_slice(body)?;
+    let (Some(heartbeat), Some(monitor)) = (notification.heartbeat, notification.monitor) else {
+        return Ok(WebhookPoke {
+            title: Some("Uptime Kuma".to_string()),
+            message: notification.msg,
+            ..Default::default()
+        });
+    };
+
+    let (state, priority, tag) = match heartbeat.status {
+        0 => ("Down", Some(4), "red_circle"),
+        1 => ("Up", None, "green_circle"),
+        2 => ("Pending", None, "yellow_circle"),
+        _ => ("Maintenance", None, "wrench"),
+    };
+    // The URL is "https://" for monitors that don't have one
+    let url = monitor.url.filter(|url| url != "https://");
+    let target = monitor
+        .hostname
+        .filter(|_| url.is_none())
+        .map(|hostname| format!(" `{}`", hostname))
+        .unwrap_or_default();
+    let ping = heartbeat
+        .ping
+        .map(|ping| format!(" ({}ms)", ping))
+        .unwrap_or_default();
+
+    Ok(WebhookPoke {
+        title: Some(format!("[{}] {}", state, monitor.name)),
+        message: format!("{}{}{}", heartbeat.msg, target, ping),
+        priority,
+        tags: Some(vec![tag.to_string()]),
+        click: url,
+        // Recoveries are threaded under the outage
+        group: Some(format!("uptime-kuma {}", monitor.id)),
+    })
+}